@@ -0,0 +1,167 @@
+//! Latency of fetching posts while other clients are logging in.
+//!
+//! Needs `DATABASE_URL` to point to a migrated database the benchmark may add
+//! a user to. Run with `cargo bench -p backend --bench fetch_latency`, the
+//! number of concurrent logins can be set with `BENCH_LOGINS`.
+
+extern crate actix;
+extern crate backend;
+extern crate diesel;
+extern crate dotenv;
+extern crate futures;
+extern crate r2d2;
+
+use actix::prelude::*;
+use backend::{
+    config,
+    database::executor::{CreateUser, DbExecutor, FetchPosts, FindUser},
+    hasher::{HashPassword, Hasher, Verified, VerifyPassword},
+    pagination::{Order, PageSize},
+};
+use diesel::{prelude::*, r2d2::ConnectionManager};
+use dotenv::dotenv;
+use futures::Future;
+use r2d2::Pool;
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+const USERNAME: &str = "fetch_latency_bench";
+const PASSWORD: &str = "bench Passw0rd";
+const SAMPLES: usize = 200;
+
+fn main() {
+    dotenv().ok();
+    let database_url = match env::var("DATABASE_URL") {
+        Ok(url) => url,
+        Err(_) => {
+            eprintln!("Skipping benchmark, DATABASE_URL is not set");
+            return;
+        }
+    };
+    let logins = config::var_or("BENCH_LOGINS", 8);
+
+    let _system = System::new("fetch latency");
+    let db_threads = config::var_or("DB_THREADS", 4);
+    let pool = Pool::builder()
+        .max_size(db_threads as u32)
+        .build(ConnectionManager::<PgConnection>::new(database_url))
+        .expect("Failed to create pool");
+    let db = SyncArbiter::start(db_threads, move || DbExecutor(pool.clone()));
+    let hasher = Hasher::start_from_env().expect("Invalid password hashing configuration");
+
+    let user_id = bench_user(&db, &hasher);
+
+    let idle = measure(&db, user_id);
+
+    let running = Arc::new(AtomicBool::new(true));
+    let workers = (0..logins)
+        .map(|_| {
+            let (db, hasher, running) = (db.clone(), hasher.clone(), running.clone());
+            thread::spawn(move || {
+                while running.load(Ordering::Relaxed) {
+                    login(&db, &hasher);
+                }
+            })
+        }).collect::<Vec<_>>();
+
+    // Let the logins pile up before measuring
+    thread::sleep(Duration::from_millis(500));
+    let loaded = measure(&db, user_id);
+
+    running.store(false, Ordering::Relaxed);
+    for worker in workers {
+        worker.join().expect("Login thread panicked");
+    }
+
+    report("idle", &idle);
+    report(&format!("{} concurrent logins", logins), &loaded);
+}
+
+/// Find the user the benchmark logs in as, creating it on the first run
+fn bench_user(db: &Addr<DbExecutor>, hasher: &Addr<Hasher>) -> i32 {
+    let existing = db
+        .send(FindUser {
+            username: USERNAME.to_string(),
+        }).wait()
+        .unwrap()
+        .expect("Failed to look up the benchmark user");
+    if let Some(user) = existing {
+        return user.id;
+    }
+
+    let password_hash = hasher
+        .send(HashPassword {
+            password: PASSWORD.to_string(),
+        }).wait()
+        .unwrap()
+        .expect("Failed to hash the benchmark password");
+    db.send(CreateUser {
+        username: USERNAME.to_string(),
+        password_hash,
+    }).wait()
+    .unwrap()
+    .expect("Failed to create the benchmark user")
+    .id
+}
+
+/// The same work the login path does, without starting a session
+fn login(db: &Addr<DbExecutor>, hasher: &Addr<Hasher>) {
+    let user = db
+        .send(FindUser {
+            username: USERNAME.to_string(),
+        }).wait()
+        .unwrap()
+        .unwrap()
+        .expect("Benchmark user disappeared");
+    let verified = hasher
+        .send(VerifyPassword {
+            password: PASSWORD.to_string(),
+            hash: Some(user.password),
+        }).wait()
+        .unwrap()
+        .unwrap();
+    if let Verified::Incorrect = verified {
+        panic!("Benchmark password was rejected");
+    }
+}
+
+fn measure(db: &Addr<DbExecutor>, user_id: i32) -> Vec<Duration> {
+    let mut samples = (0..SAMPLES)
+        .map(|_| {
+            let start = Instant::now();
+            db.send(FetchPosts {
+                user_id,
+                room_id: 0,
+                after: None,
+                limit: PageSize::default().default,
+                order: Order::NewestFirst,
+            })
+                .wait()
+                .unwrap()
+                .expect("Failed to fetch posts");
+            start.elapsed()
+        }).collect::<Vec<_>>();
+    samples.sort();
+    samples
+}
+
+fn report(label: &str, samples: &[Duration]) {
+    let millis = |d: Duration| d.as_secs() as f64 * 1e3 + f64::from(d.subsec_nanos()) / 1e6;
+    let percentile = |p: usize| millis(samples[(samples.len() - 1) * p / 100]);
+
+    println!(
+        "fetch posts, {}: p50 {:.2}ms, p95 {:.2}ms, p99 {:.2}ms, max {:.2}ms",
+        label,
+        percentile(50),
+        percentile(95),
+        percentile(99),
+        percentile(100)
+    );
+}
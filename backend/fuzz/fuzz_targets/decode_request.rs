@@ -0,0 +1,25 @@
+//! Feeds arbitrary frames through the decoding a websocket does before it
+//! dispatches a request, which has to reject anything malformed without
+//! panicking. Run with `cargo fuzz run decode_request` from `backend`.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate backend;
+extern crate capnp;
+
+use backend::{
+    request::{Reply, Request},
+    ServerError,
+};
+use capnp::serialize_packed;
+
+fuzz_target!(|data: &[u8]| {
+    let builder = match Request::decode(data) {
+        Ok((_, request)) => request.reply().error(&ServerError::InvalidSession.into()),
+        Err(e) => Reply::Invalid.error(&e),
+    };
+
+    let mut res = Vec::new();
+    serialize_packed::write_message(&mut res, &builder).expect("Unable to write response");
+});
@@ -0,0 +1,92 @@
+//! Feeds arbitrary bodies to the `/login` handler, which decodes a request,
+//! dispatches it to the `AuthService` and answers it, all without panicking.
+//! The database is never reachable, so requests fail once they need it. Run
+//! with `cargo fuzz run login_request` from `backend`.
+//!
+//! The websocket's `handle_request` is not driven directly: it answers through
+//! the `WebsocketContext` of an upgraded connection, which only exists inside
+//! a running server, and starting one per input is too slow to fuzz. Its
+//! decoding is what `decode_request` covers, and the requests it shares with
+//! `/login` go through the same `AuthService` as here.
+
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+#[macro_use]
+extern crate lazy_static;
+extern crate actix;
+extern crate actix_web;
+extern crate backend;
+extern crate diesel;
+extern crate r2d2;
+
+use actix::prelude::*;
+use actix_web::test::TestRequest;
+use backend::{
+    auth::AuthService,
+    chatserver::ChatServer,
+    database::executor::DbExecutor,
+    hasher::{Hasher, Scheme},
+    server::login_register,
+    session::SessionCache,
+    throttle::{Limits, LoginThrottle},
+    validation::Policy,
+    State,
+};
+use diesel::{prelude::*, r2d2::ConnectionManager};
+use r2d2::Pool;
+use std::{
+    sync::{mpsc, Mutex},
+    thread,
+    time::Duration,
+};
+
+lazy_static! {
+    /// The actors behind the state, running on a system of their own
+    static ref STATE: Mutex<State> = Mutex::new(start());
+}
+
+fn start() -> State {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        System::run(move || {
+            let manager = ConnectionManager::<PgConnection>::new("postgres://localhost/unused");
+            let pool = Pool::builder()
+                .min_idle(Some(0))
+                .connection_timeout(Duration::from_millis(1))
+                .build_unchecked(manager);
+            let db = SyncArbiter::start(1, move || DbExecutor(pool.clone()));
+            // The cheapest hashes, inputs are meant to reach the handlers
+            let scheme = Scheme::Bcrypt { cost: 4 };
+            let dummy_hash = scheme.hash("dummy password").unwrap();
+            let hasher = SyncArbiter::start(1, move || Hasher::new(scheme, dummy_hash.clone()));
+            let db_clone = db.clone();
+            let chat = Arbiter::start(move |_| ChatServer::new(db_clone));
+            let auth = AuthService::new(
+                db.clone(),
+                hasher,
+                SessionCache::new(Duration::from_secs(60)),
+                Policy::default(),
+                LoginThrottle::new(Limits::default()),
+            );
+            tx.send(State { db, chat, auth }).unwrap();
+        });
+    });
+    rx.recv().unwrap()
+}
+
+fn state() -> State {
+    let state = STATE.lock().unwrap();
+    State {
+        db: state.db.clone(),
+        chat: state.chat.clone(),
+        auth: state.auth.clone(),
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut sys = System::new("fuzz");
+    let req = TestRequest::with_state(state()).set_payload(data.to_vec()).finish();
+    // Errors are answered in the body, only an invalid request kind is not
+    let _ = sys.block_on(login_register(&req));
+});
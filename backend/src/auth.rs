@@ -0,0 +1,467 @@
+//! Authentication shared by the `/login` route and the websocket actor.
+//!
+//! Every way of obtaining a session goes through `AuthService`, so logging in
+//! and registering always create a session row the refresh and revocation
+//! checks can rely on.
+
+use super::{
+    database::{
+        executor::{
+            ChangePassword, CreateSession, CreateUser, DbExecutor, DeleteSession, DeleteUser,
+            DeleteUserSessions, FindUser, FindUserID, RefreshSession, UpdatePasswordHash,
+        },
+        models::{Post, User},
+    },
+    hasher::{HashPassword, Hasher, Verified, VerifyPassword},
+    session::{ClientInfo, SessionCache},
+    throttle::LoginThrottle,
+    token::{SessionTokens, Token},
+    validation::{self, Policy},
+    ServerError,
+};
+use actix::prelude::*;
+use capnp::message::{Builder, HeapAllocator};
+use failure::Error;
+use futures::{future, Future};
+use protocol_capnp::{response, user};
+
+pub type AuthFuture<T> = Box<Future<Item = T, Error = Error>>;
+
+/// A user together with the tokens of their session
+pub struct Authenticated {
+    pub user: User,
+    pub tokens: SessionTokens,
+}
+
+#[derive(Clone)]
+pub struct AuthService {
+    db: Addr<DbExecutor>,
+    hasher: Addr<Hasher>,
+    sessions: SessionCache,
+    policy: Policy,
+    throttle: LoginThrottle,
+}
+
+impl AuthService {
+    pub fn new(
+        db: Addr<DbExecutor>,
+        hasher: Addr<Hasher>,
+        sessions: SessionCache,
+        policy: Policy,
+        throttle: LoginThrottle,
+    ) -> Self {
+        AuthService {
+            db,
+            hasher,
+            sessions,
+            policy,
+            throttle,
+        }
+    }
+
+    /// Check a user's credentials and start a new session
+    ///
+    /// Attempts are throttled per IP and username, see `LoginThrottle`. A
+    /// password hash made with an outdated scheme is replaced on the way.
+    ///
+    /// An unknown username and a wrong password are both reported as
+    /// `InvalidCredentials` and take equally long, only the audit log tells
+    /// them apart.
+    pub fn login(
+        &self,
+        username: String,
+        password: String,
+        client: ClientInfo,
+    ) -> AuthFuture<Authenticated> {
+        let db = self.db.clone();
+        let hasher = self.hasher.clone();
+        let session_db = self.db.clone();
+        let (name, ip) = (username.clone(), client.ip_address.clone());
+        Box::new(
+            Self::throttled(
+                &self.throttle,
+                client.ip_address.clone(),
+                username.clone(),
+                move || -> AuthFuture<User> {
+                    Box::new(
+                        db.send(FindUser { username })
+                            .from_err()
+                            .and_then(|res| res)
+                            .and_then(move |user| {
+                                Self::check_password(&db, &hasher, user, password)
+                            }),
+                    )
+                },
+            ).then(move |res| {
+                audit_login(&name, ip.as_ref().map(String::as_str), &res);
+                res.map_err(|e| {
+                    if is_credential_failure(&e) {
+                        ServerError::InvalidCredentials.into()
+                    } else {
+                        e
+                    }
+                })
+            }).and_then(move |user| Self::start_session(&session_db, user, client)),
+        )
+    }
+
+    /// Create a new user and start their first session
+    ///
+    /// The username and password have to satisfy the registration policy.
+    pub fn register(
+        &self,
+        username: String,
+        password: String,
+        client: ClientInfo,
+    ) -> AuthFuture<Authenticated> {
+        let username = match self.policy.username(&username) {
+            Ok(username) => username,
+            Err(e) => return Box::new(future::err(e.into())),
+        };
+        if let Err(e) = self.policy.password(&password, &username) {
+            return Box::new(future::err(e.into()));
+        }
+
+        let db = self.db.clone();
+        Box::new(
+            self.hasher
+                .send(HashPassword { password })
+                .from_err()
+                .and_then(|res| res)
+                .and_then(move |password_hash| {
+                    db.send(CreateUser {
+                        username,
+                        password_hash,
+                    }).from_err()
+                    .and_then(|res| res)
+                    .and_then(move |user| Self::start_session(&db, user, client))
+                }),
+        )
+    }
+
+    /// Continue a session with an access token that is still valid
+    ///
+    /// No new tokens are issued, the refresh token is left empty.
+    pub fn resume(&self, token: &str) -> AuthFuture<Authenticated> {
+        let db = self.db.clone();
+        let access = token.to_string();
+        Box::new(self.verify(token).and_then(move |token| {
+            db.send(FindUserID {
+                user_id: token.user_id(),
+            }).from_err()
+            .and_then(move |res| -> Result<Authenticated, Error> {
+                Ok(Authenticated {
+                    user: res?.ok_or(ServerError::FindUser)?,
+                    tokens: SessionTokens {
+                        access,
+                        refresh: String::new(),
+                    },
+                })
+            })
+        }))
+    }
+
+    /// Trade a refresh token for a new pair of tokens
+    pub fn refresh(&self, refresh_token: String, client: ClientInfo) -> AuthFuture<Authenticated> {
+        let db = self.db.clone();
+        Box::new(
+            self.db
+                .send(RefreshSession {
+                    refresh_token,
+                    client,
+                }).from_err()
+                .and_then(|res| res)
+                .and_then(move |(tokens, user_id)| {
+                    db.send(FindUserID { user_id })
+                        .from_err()
+                        .and_then(move |res| -> Result<Authenticated, Error> {
+                            Ok(Authenticated {
+                                user: res?.ok_or(ServerError::FindUser)?,
+                                tokens,
+                            })
+                        })
+                }),
+        )
+    }
+
+    /// Verify an access token, including that its session was not revoked
+    pub fn verify(&self, token: &str) -> AuthFuture<Token> {
+        self.sessions.verify(&self.db, token)
+    }
+
+    /// End the session an access token belongs to
+    pub fn logout(&self, token: &str) -> AuthFuture<()> {
+        let db = self.db.clone();
+        let sessions = self.sessions.clone();
+        Box::new(self.verify(token).and_then(move |token| {
+            db.send(DeleteSession {
+                session_id: token.session_id().to_string(),
+            }).from_err()
+            .and_then(move |res| -> Result<(), Error> {
+                res?;
+                sessions.remove(token.session_id());
+                Ok(())
+            })
+        }))
+    }
+
+    /// End every session of the user an access token belongs to
+    pub fn logout_all(&self, token: &str) -> AuthFuture<()> {
+        let db = self.db.clone();
+        let sessions = self.sessions.clone();
+        Box::new(self.verify(token).and_then(move |token| {
+            db.send(DeleteUserSessions {
+                user_id: token.user_id(),
+            }).from_err()
+            .and_then(move |res| -> Result<(), Error> {
+                res?;
+                sessions.remove_user(token.user_id());
+                Ok(())
+            })
+        }))
+    }
+
+    /// Set a new password, ending every session but the current one
+    ///
+    /// The current password has to be given and is throttled like a login.
+    pub fn change_password(
+        &self,
+        token: &str,
+        current_password: String,
+        new_password: String,
+        client: ClientInfo,
+    ) -> AuthFuture<()> {
+        let db = self.db.clone();
+        let hasher = self.hasher.clone();
+        let sessions = self.sessions.clone();
+        let policy = self.policy.clone();
+        Box::new(self.reauthenticate(token, current_password, client).and_then(
+            move |(token, user)| -> AuthFuture<()> {
+                if let Err(e) = policy.password(&new_password, &user.username) {
+                    return Box::new(future::err(e.into()));
+                }
+
+                let (user_id, session_id) = (token.user_id(), token.session_id().to_string());
+                Box::new(
+                    hasher
+                        .send(HashPassword {
+                            password: new_password,
+                        }).from_err()
+                        .and_then(|res| res)
+                        .and_then(move |password_hash| {
+                            db.send(ChangePassword {
+                                user_id,
+                                session_id,
+                                password_hash,
+                            }).from_err()
+                            .and_then(|res| res)
+                        }).map(move |()| sessions.remove_user(user_id)),
+                )
+            },
+        ))
+    }
+
+    /// Delete the account an access token belongs to
+    ///
+    /// Returns the id of the user and their posts that were still open.
+    pub fn delete_account(
+        &self,
+        token: &str,
+        password: String,
+        client: ClientInfo,
+    ) -> AuthFuture<(i32, Vec<Post>)> {
+        let db = self.db.clone();
+        let sessions = self.sessions.clone();
+        Box::new(
+            self.reauthenticate(token, password, client)
+                .and_then(move |(_, user)| {
+                    db.send(DeleteUser { user_id: user.id })
+                        .from_err()
+                        .and_then(|res| res)
+                        .map(move |open_posts| {
+                            sessions.remove_user(user.id);
+                            (user.id, open_posts)
+                        })
+                }),
+        )
+    }
+
+    /// Forget a session that was revoked by other means
+    pub fn forget_session(&self, session_id: &str) {
+        self.sessions.remove(session_id);
+    }
+
+    /// Verify an access token and the password of the user it belongs to
+    fn reauthenticate(
+        &self,
+        token: &str,
+        password: String,
+        client: ClientInfo,
+    ) -> AuthFuture<(Token, User)> {
+        let db = self.db.clone();
+        let hasher = self.hasher.clone();
+        let throttle = self.throttle.clone();
+        Box::new(self.verify(token).and_then(move |token| {
+            db.send(FindUserID {
+                user_id: token.user_id(),
+            }).from_err()
+            .and_then(|res| -> Result<User, Error> { Ok(res?.ok_or(ServerError::FindUser)?) })
+            .and_then(move |user| {
+                Self::throttled(
+                    &throttle,
+                    client.ip_address,
+                    user.username.clone(),
+                    move || Self::check_password(&db, &hasher, Some(user), password),
+                )
+            }).map(move |user| (token, user))
+        }))
+    }
+
+    /// Run a password check unless the IP or username is throttled, counting
+    /// it unless it succeeds or fails for another reason than the credentials
+    fn throttled<F>(
+        throttle: &LoginThrottle,
+        ip: Option<String>,
+        username: String,
+        attempt: F,
+    ) -> AuthFuture<User>
+    where
+        F: FnOnce() -> AuthFuture<User>,
+    {
+        if let Err(e) = throttle.check(ip.as_ref().map(String::as_str), &username) {
+            return Box::new(future::err(e.into()));
+        }
+
+        let throttle = throttle.clone();
+        Box::new(attempt().then(move |res| {
+            let ip = ip.as_ref().map(String::as_str);
+            match res {
+                Ok(_) => throttle.succeeded(ip, &username),
+                // Already counted by the check
+                Err(ref e) if is_credential_failure(e) => (),
+                Err(_) => throttle.released(ip, &username),
+            }
+            res
+        }))
+    }
+
+    /// Check a password against a user's hash, replacing the hash if it was
+    /// made with an outdated scheme
+    ///
+    /// Without a user the password is checked against a dummy hash, so the
+    /// answer takes as long as for a wrong password.
+    fn check_password(
+        db: &Addr<DbExecutor>,
+        hasher: &Addr<Hasher>,
+        user: Option<User>,
+        password: String,
+    ) -> AuthFuture<User> {
+        let db = db.clone();
+        Box::new(
+            hasher
+                .send(VerifyPassword {
+                    password,
+                    hash: user.as_ref().map(|user| user.password.clone()),
+                }).from_err()
+                .and_then(move |res| -> Result<User, Error> {
+                    let verified = res?;
+                    let user = user.ok_or(ServerError::UnknownUser)?;
+                    match verified {
+                        Verified::Incorrect => Err(ServerError::IncorrectPassword.into()),
+                        Verified::Correct => Ok(user),
+                        Verified::Rehashed(new_hash) => {
+                            db.do_send(UpdatePasswordHash {
+                                user_id: user.id,
+                                old_hash: user.password.clone(),
+                                new_hash,
+                            });
+                            Ok(user)
+                        }
+                    }
+                }),
+        )
+    }
+
+    fn start_session(
+        db: &Addr<DbExecutor>,
+        user: User,
+        client: ClientInfo,
+    ) -> AuthFuture<Authenticated> {
+        Box::new(
+            db.send(CreateSession {
+                user_id: user.id,
+                client,
+            }).from_err()
+            .and_then(move |res| -> Result<Authenticated, Error> {
+                Ok(Authenticated {
+                    user,
+                    tokens: res?,
+                })
+            }),
+        )
+    }
+}
+
+fn is_credential_failure(e: &Error) -> bool {
+    match e.downcast_ref::<ServerError>() {
+        Some(ServerError::UnknownUser) | Some(ServerError::IncorrectPassword) => true,
+        _ => false,
+    }
+}
+
+/// Record the outcome of a login attempt under the `audit` log target
+fn audit_login(username: &str, ip: Option<&str>, res: &Result<User, Error>) {
+    let ip = ip.unwrap_or("unknown");
+    let e = match *res {
+        Ok(ref user) => {
+            info!(target: "audit", "login succeeded user_id={} ip={}", user.id, ip);
+            return;
+        }
+        Err(ref e) => e,
+    };
+
+    let reason = match e.downcast_ref::<ServerError>() {
+        Some(ServerError::UnknownUser) => "unknown_user",
+        Some(ServerError::IncorrectPassword) => "wrong_password",
+        Some(ServerError::LoginLocked { .. }) => "locked",
+        _ => "error",
+    };
+    warn!(
+        target: "audit",
+        "login failed reason={} username={} ip={}",
+        reason,
+        validation::username_digest(username),
+        ip
+    );
+}
+
+/// Fill in the response to a successful login or registration
+pub fn write_login_success(builder: &mut Builder<HeapAllocator>, auth: &Authenticated) {
+    let mut success = builder
+        .init_root::<response::Builder>()
+        .init_login()
+        .init_success();
+
+    success.set_token(&auth.tokens.access);
+    success.set_refresh_token(&auth.tokens.refresh);
+    set_user(success.init_user(), &auth.user);
+}
+
+/// Fill in the response to a successful session refresh
+pub fn write_refresh_success(builder: &mut Builder<HeapAllocator>, auth: &Authenticated) {
+    let mut success = builder
+        .init_root::<response::Builder>()
+        .init_refresh_session()
+        .init_success();
+
+    success.set_token(&auth.tokens.access);
+    success.set_refresh_token(&auth.tokens.refresh);
+    set_user(success.init_user(), &auth.user);
+}
+
+fn set_user(mut u: user::Builder, user: &User) {
+    u.set_id(user.id);
+    u.set_username(&user.username);
+    u.set_karma(user.karma);
+    u.set_streak(user.streak);
+}
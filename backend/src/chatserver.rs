@@ -1,35 +1,68 @@
 //! ChatServer is an actor. It maintains a list of connected client sessions.
 //! Also manages available rooms. Peers can send messages to other peers
 //! the same room through ChatServer.
-
-use super::database::{
-    executor::{DbExecutor, UpdateKarma},
-    models::{Post, User},
+//!
+//! Every session starts out in the general room and can join and leave other
+//! rooms. New and closed posts are only sent to the members of their room.
+//! The connection of a session keeps track of the rooms it is in and sends
+//! them along whenever it registers, so a session registered again after
+//! logging in anew or being evicted stays in its rooms.
+//!
+//! Sessions send a `Heartbeat` while their client is responsive. A session
+//! whose heartbeats stop for `CHAT_SESSION_TIMEOUT` seconds, or whose actor
+//! is gone when a message is sent to it, is evicted even if it never sent a
+//! `Disconnect`.
+//!
+//! Every session belongs to the user whose token it connected with, and a
+//! user can have several sessions open, one per device. `SendTo` delivers a
+//! message to everyone, one session, every session of a user, everyone but a
+//! session or user, or the members of a room. Connections send new posts and
+//! vote tallies to the members of their room through it. `DisconnectUser`
+//! ends every session of a user and closes their connections, like when the
+//! account is deleted.
+//!
+//! The number of connected and evicted sessions is logged every
+//! `CHAT_METRICS_INTERVAL` seconds.
+//!
+//! Changes to the vote tally of a post are sent to the members of its room as
+//! they happen. With `VOTE_TALLIES_HIDDEN` set, tallies are kept from clients
+//! until voting on the post closes, and sent for every post closed.
+
+use super::{
+    config,
+    database::{
+        executor::{DbExecutor, UpdateKarma},
+        models::{Post, User},
+    },
 };
-use actix::{fut, prelude::*};
+use actix::{fut, prelude::*, SendError};
 use capnp::{message::Builder, serialize_packed};
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
 use uuid::Uuid;
 
-use protocol_capnp::{response, Vote as P_Vote};
+use protocol_capnp::{post as P_Post, response, update, vote_tally, Vote as P_Vote};
 
 // Chat Server sends message of this type to sessions
 #[derive(Message)]
-pub struct ServerMessage(pub Vec<u8>, pub Option<String>);
+pub struct ServerMessage(pub Vec<u8>);
 
-// Message from client that will be broadcasted
+/// The session was ended by the chat server, its connection should close
 #[derive(Message)]
-pub struct ClientMessage {
-    /// Id of client session
-    pub id: String,
-    /// Peer message
-    pub msg: Post,
-}
+pub struct Close;
 
 // New chat session is created
 #[derive(Message)]
 #[rtype(String)]
 pub struct Connect {
     pub addr: Recipient<ServerMessage>,
+    pub close: Recipient<Close>,
+    /// The user the session was verified to belong to
+    pub user_id: i32,
+    /// The rooms the connection is in
+    pub rooms: HashSet<i32>,
 }
 
 /// Session is disconnected
@@ -38,50 +71,331 @@ pub struct Disconnect {
     pub id: String,
 }
 
+/// Every session of a user is ended, their connections are closed
+#[derive(Message)]
+pub struct DisconnectUser {
+    pub user_id: i32,
+}
+
+/// Posts were deleted and should disappear from every client
+#[derive(Message)]
+pub struct RemovePosts {
+    pub posts: Vec<Post>,
+}
+
+/// Whether vote tallies are kept from clients while voting is open
+pub fn tallies_hidden() -> bool {
+    config::var_or("VOTE_TALLIES_HIDDEN", false)
+}
+
+/// Fill in a post as it is sent to clients, `vote` being the one the client
+/// cast on it
+pub fn write_post(mut builder: P_Post::Builder, post: &Post, vote: P_Vote, hide_tallies: bool) {
+    builder.set_id(post.id);
+    builder.set_content(&post.content);
+    builder.set_valid(post.valid);
+    builder.set_user_id(post.user_id);
+    builder.set_room_id(post.room_id);
+    builder.set_vote(vote);
+    if hide_tallies && post.valid {
+        builder.set_tallies_hidden(true);
+    } else {
+        builder.set_up_count(post.up_count);
+        builder.set_down_count(post.down_count);
+    }
+}
+
+/// Fill in the tally of a post
+pub fn write_tally(mut builder: vote_tally::Builder, post: &Post) {
+    builder.set_post_id(post.id);
+    builder.set_up_count(post.up_count);
+    builder.set_down_count(post.down_count);
+}
+
+/// Serialize an update pushed to clients
+pub fn write_update<F>(init: F) -> Result<Vec<u8>, capnp::Error>
+where
+    F: FnOnce(update::Builder),
+{
+    let mut b = Builder::new_default();
+    init(b.init_root::<response::Builder>().init_update());
+
+    let mut data = Vec::new();
+    serialize_packed::write_message(&mut data, &b)?;
+    Ok(data)
+}
+
+/// The room every session is in when it connects
+pub const GENERAL_ROOM: i32 = 0;
+
+/// A session starts receiving the posts of a room
+#[derive(Message)]
+pub struct JoinRoom {
+    pub id: String,
+    pub room_id: i32,
+}
+
+/// A session stops receiving the posts of a room
+#[derive(Message)]
+pub struct LeaveRoom {
+    pub id: String,
+    pub room_id: i32,
+}
+
+/// The client of a session is still responsive
+///
+/// Carries the address and rooms too, so a session evicted by mistake rejoins
+/// the rooms it was in with its next heartbeat.
+#[derive(Message)]
+pub struct Heartbeat {
+    pub id: String,
+    pub addr: Recipient<ServerMessage>,
+    pub close: Recipient<Close>,
+    pub user_id: i32,
+    pub rooms: HashSet<i32>,
+}
+
+/// The sessions a message is sent to
+#[derive(Clone, Debug, PartialEq)]
+pub enum Audience {
+    All,
+    /// A single session, one device of a user
+    Session(String),
+    /// Every session of a user
+    User(i32),
+    AllExceptSession(String),
+    AllExceptUser(i32),
+    /// Every session that joined a room
+    Room(i32),
+    /// Every session that joined a room, except one
+    RoomExceptSession(i32, String),
+}
+
+/// Send a serialized `Response` to some of the sessions
+#[derive(Message)]
+pub struct SendTo {
+    pub audience: Audience,
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct ChatMetrics {
+    /// Sessions currently connected
+    pub connected: usize,
+    /// Sessions evicted because their actor was gone
+    pub evicted_closed: u64,
+    /// Sessions evicted because their heartbeats stopped
+    pub evicted_timeout: u64,
+}
+
+struct Session {
+    addr: Recipient<ServerMessage>,
+    close: Recipient<Close>,
+    user_id: i32,
+    last_heartbeat: Instant,
+    rooms: HashSet<i32>,
+}
+
+impl Session {
+    fn new(
+        addr: Recipient<ServerMessage>,
+        close: Recipient<Close>,
+        user_id: i32,
+        rooms: HashSet<i32>,
+    ) -> Self {
+        Session {
+            addr,
+            close,
+            user_id,
+            last_heartbeat: Instant::now(),
+            rooms,
+        }
+    }
+}
+
 pub struct ChatServer {
-    session_ids: Vec<String>,
-    session_addrs: Vec<Recipient<ServerMessage>>,
+    sessions: HashMap<String, Session>,
+    /// Ids of the sessions of each user
+    users: HashMap<i32, HashSet<String>>,
     db: Addr<DbExecutor>,
+    session_timeout: Duration,
+    /// Time between two logs of the metrics
+    metrics_interval: Duration,
+    hide_tallies: bool,
+    evicted_closed: u64,
+    evicted_timeout: u64,
 }
 
 impl ChatServer {
     pub fn new(addr: Addr<DbExecutor>) -> Self {
         ChatServer {
-            session_ids: Vec::new(),
-            session_addrs: Vec::new(),
+            sessions: HashMap::new(),
+            users: HashMap::new(),
             db: addr,
+            session_timeout: Duration::from_secs(config::var_or("CHAT_SESSION_TIMEOUT", 30)),
+            metrics_interval: Duration::from_secs(config::var_or("CHAT_METRICS_INTERVAL", 300)),
+            hide_tallies: tallies_hidden(),
+            evicted_closed: 0,
+            evicted_timeout: 0,
         }
     }
 
-    fn send_message(&self, data: &[u8], skip: &Option<String>) {
-        for addr in &self.session_addrs {
-            println!("Server message sent to session!");
-            let _ = addr.do_send(ServerMessage(data.to_vec(), skip.clone()));
+    fn metrics(&self) -> ChatMetrics {
+        ChatMetrics {
+            connected: self.sessions.len(),
+            evicted_closed: self.evicted_closed,
+            evicted_timeout: self.evicted_timeout,
         }
     }
 
-    fn send_updates(&self, (invalid, users): (Vec<Post>, Vec<User>)) {
-        let mut b = Builder::new_default();
-        let mut data = Vec::new();
-        {
-            let update = b.init_root::<response::Builder>().init_update();
+    fn insert_session(&mut self, id: String, session: Session) {
+        self.users
+            .entry(session.user_id)
+            .or_insert_with(HashSet::new)
+            .insert(id.clone());
+        self.sessions.insert(id, session);
+    }
 
-            let mut invalid_posts = update.init_invalid(invalid.len() as u32);
+    fn remove_session(&mut self, id: &str) -> Option<Session> {
+        let session = self.sessions.remove(id)?;
+        let now_empty = match self.users.get_mut(&session.user_id) {
+            Some(ids) => {
+                ids.remove(id);
+                ids.is_empty()
+            }
+            None => false,
+        };
+        if now_empty {
+            self.users.remove(&session.user_id);
+        }
+        Some(session)
+    }
+
+    /// Ids of the sessions in an audience
+    fn audience(&self, audience: &Audience) -> Vec<String> {
+        match *audience {
+            Audience::All => self.sessions.keys().cloned().collect(),
+            Audience::Session(ref id) if self.sessions.contains_key(id) => vec![id.clone()],
+            Audience::Session(_) => Vec::new(),
+            Audience::User(user_id) => self
+                .users
+                .get(&user_id)
+                .map(|ids| ids.iter().cloned().collect())
+                .unwrap_or_default(),
+            Audience::AllExceptSession(ref skip) => self
+                .sessions
+                .keys()
+                .filter(|id| *id != skip)
+                .cloned()
+                .collect(),
+            Audience::AllExceptUser(user_id) => self
+                .sessions
+                .iter()
+                .filter(|(_, session)| session.user_id != user_id)
+                .map(|(id, _)| id.clone())
+                .collect(),
+            Audience::Room(room_id) => self
+                .sessions
+                .iter()
+                .filter(|(_, session)| session.rooms.contains(&room_id))
+                .map(|(id, _)| id.clone())
+                .collect(),
+            Audience::RoomExceptSession(room_id, ref skip) => self
+                .sessions
+                .iter()
+                .filter(|(id, session)| *id != skip && session.rooms.contains(&room_id))
+                .map(|(id, _)| id.clone())
+                .collect(),
+        }
+    }
 
-            for (i, post) in invalid.iter().enumerate() {
-                invalid_posts.set(i as u32, post.id);
+    fn send_message(&mut self, data: &[u8], audience: &Audience) {
+        let mut closed = Vec::new();
+        for id in self.audience(audience) {
+            trace!("Server message sent to session");
+            if let Err(SendError::Closed(_)) = self.sessions[&id]
+                .addr
+                .do_send(ServerMessage(data.to_vec()))
+            {
+                closed.push(id);
             }
         }
 
-        if let Ok(()) = serialize_packed::write_message(&mut data, &b) {
-            self.send_message(&data, &None);
+        for id in closed {
+            debug!("Evicting chat session {}, its actor is gone", id);
+            self.remove_session(&id);
+            self.evicted_closed += 1;
+        }
+    }
+
+    /// Evict the sessions whose heartbeats stopped
+    fn evict_lapsed(&mut self) {
+        let timeout = self.session_timeout;
+        let lapsed: Vec<String> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.last_heartbeat.elapsed() > timeout)
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        let evicted = lapsed.len();
+        for id in lapsed {
+            self.remove_session(&id);
+        }
+
+        if evicted > 0 {
+            self.evicted_timeout += evicted as u64;
+            info!(
+                "Evicted {} chat sessions without a heartbeat, {:?}",
+                evicted,
+                self.metrics()
+            );
+        }
+    }
+
+    /// Tell the members of each room which of its posts are gone
+    fn send_invalid(&mut self, posts: &[Post]) {
+        let mut rooms: HashMap<i32, Vec<i32>> = HashMap::new();
+        for post in posts {
+            rooms.entry(post.room_id).or_insert_with(Vec::new).push(post.id);
+        }
+
+        for (room_id, post_ids) in rooms {
+            self.send_invalid_in(room_id, &post_ids);
+        }
+    }
+
+    fn send_invalid_in(&mut self, room_id: i32, post_ids: &[i32]) {
+        let data = write_update(|update| {
+            let mut invalid_posts = update.init_invalid(post_ids.len() as u32);
+
+            for (i, id) in post_ids.iter().enumerate() {
+                invalid_posts.set(i as u32, *id);
+            }
+        });
+
+        if let Ok(data) = data {
+            self.send_message(&data, &Audience::Room(room_id));
         }
+    }
 
-        data.clear();
+    /// Tell the members of the room of a post how it was voted on
+    fn send_tally(&mut self, post: &Post) {
+        if let Ok(data) = write_update(|update| write_tally(update.init_vote_tally(), post)) {
+            self.send_message(&data, &Audience::Room(post.room_id));
+        }
+    }
 
-        {
-            let update = b.init_root::<response::Builder>().init_update();
+    fn send_updates(&mut self, (invalid, users): (Vec<Post>, Vec<User>)) {
+        // Voting on these is over, so their tallies are no secret anymore
+        if self.hide_tallies {
+            for post in &invalid {
+                self.send_tally(post);
+            }
+        }
+        self.send_invalid(&invalid);
 
+        let data = write_update(|update| {
             let mut users_to_update = update.init_users(users.len() as u32);
 
             for (i, usr) in users.iter().enumerate() {
@@ -91,10 +405,10 @@ impl ChatServer {
                 u.set_karma(usr.karma);
                 u.set_streak(usr.streak);
             }
-        }
+        });
 
-        if let Ok(()) = serialize_packed::write_message(&mut data, &b) {
-            self.send_message(&data, &None);
+        if let Ok(data) = data {
+            self.send_message(&data, &Audience::All);
         }
     }
 }
@@ -104,7 +418,10 @@ impl Actor for ChatServer {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        use std::time::Duration;
+        ctx.run_interval(self.session_timeout / 2, |act, _ctx| act.evict_lapsed());
+        ctx.run_interval(self.metrics_interval, |act, _ctx| {
+            info!("Chat sessions: {:?}", act.metrics());
+        });
 
         ctx.run_interval(Duration::from_secs(600), |act, ctx| {
             let query_task = act
@@ -126,31 +443,6 @@ impl Actor for ChatServer {
     }
 }
 
-// Handler for Server Messages
-impl Handler<ClientMessage> for ChatServer {
-    type Result = ();
-
-    fn handle(&mut self, msg: ClientMessage, _: &mut Context<Self>) -> Self::Result {
-        let mut b = Builder::new_default();
-        let mut data = Vec::new();
-        {
-            let update = b.init_root::<response::Builder>().init_update();
-            let p = msg.msg;
-
-            let mut post = update.init_new_post();
-            post.set_id(p.id);
-            post.set_content(&p.content);
-            post.set_valid(p.valid);
-            post.set_user_id(p.user_id);
-            post.set_vote(P_Vote::None);
-        }
-
-        let _ = serialize_packed::write_message(&mut data, &b);
-
-        self.send_message(&data, &Some(msg.id));
-    }
-}
-
 /// Handler for Connect message.
 ///
 /// Register new session and assign unique id to this session
@@ -158,22 +450,28 @@ impl Handler<Connect> for ChatServer {
     type Result = String;
 
     fn handle(&mut self, msg: Connect, _: &mut Context<Self>) -> Self::Result {
-        println!("Someone joined");
+        debug!("Chat session joined");
 
         // register session with random id
         // check to see if this session addr already exists
-        let idx = self.session_addrs.iter().position(|x| *x == msg.addr);
-        if let Some(idx) = idx {
-            // send existing id back
-            self.session_ids[idx].clone()
-        } else {
-            let id = Uuid::new_v4().to_string();
-            self.session_addrs.push(msg.addr);
-            self.session_ids.push(id.clone());
-            assert!(self.session_addrs.len() == self.session_ids.len());
-            // send new id back
-            id
-        }
+        let existing = self
+            .sessions
+            .iter()
+            .find(|(_, session)| session.addr == msg.addr)
+            .map(|(id, _)| id.clone());
+        let id = match existing {
+            Some(id) => {
+                // the connection may have logged in as someone else since
+                self.remove_session(&id);
+                id
+            }
+            None => Uuid::new_v4().to_string(),
+        };
+
+        let session = Session::new(msg.addr, msg.close, msg.user_id, msg.rooms);
+        self.insert_session(id.clone(), session);
+        // send id back
+        id
     }
 }
 
@@ -182,19 +480,371 @@ impl Handler<Disconnect> for ChatServer {
     type Result = ();
 
     fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
-        println!("Someone disconnected");
+        debug!("Chat session {} disconnected", msg.id);
+
+        self.remove_session(&msg.id);
+    }
+}
+
+/// Handler for DisconnectUser message.
+impl Handler<DisconnectUser> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: DisconnectUser, _: &mut Context<Self>) {
+        let ids = match self.users.get(&msg.user_id) {
+            Some(ids) => ids.iter().cloned().collect::<Vec<_>>(),
+            None => return,
+        };
+        debug!("Disconnecting the {} chat sessions of a user", ids.len());
+
+        for id in ids {
+            if let Some(session) = self.remove_session(&id) {
+                // A connection already gone has nothing left to close
+                let _ = session.close.do_send(Close);
+            }
+        }
+    }
+}
 
-        let idx = self.session_ids.iter().position(|x| *x == msg.id);
+/// Handler for Heartbeat message.
+impl Handler<Heartbeat> for ChatServer {
+    type Result = ();
 
-        if let Some(i) = idx {
-            // Order does not matter
-            // Can swap the id and addr of the disconnecting
-            // session with the last element and pop the last
-            // element from the vectors
-            self.session_addrs.swap_remove(i);
-            self.session_ids.swap_remove(i);
+    fn handle(&mut self, msg: Heartbeat, _: &mut Context<Self>) {
+        if let Some(session) = self.sessions.get_mut(&msg.id) {
+            session.last_heartbeat = Instant::now();
+            return;
         }
 
-        assert!(self.session_addrs.len() == self.session_ids.len());
+        debug!("Evicted chat session {} rejoined", msg.id);
+        let session = Session::new(msg.addr, msg.close, msg.user_id, msg.rooms);
+        self.insert_session(msg.id, session);
+    }
+}
+
+/// Handler for JoinRoom message.
+impl Handler<JoinRoom> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: JoinRoom, _: &mut Context<Self>) {
+        if let Some(session) = self.sessions.get_mut(&msg.id) {
+            session.rooms.insert(msg.room_id);
+        }
+    }
+}
+
+/// Handler for LeaveRoom message.
+impl Handler<LeaveRoom> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: LeaveRoom, _: &mut Context<Self>) {
+        if let Some(session) = self.sessions.get_mut(&msg.id) {
+            session.rooms.remove(&msg.room_id);
+        }
+    }
+}
+
+/// Handler for SendTo message.
+impl Handler<SendTo> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SendTo, _: &mut Context<Self>) {
+        self.send_message(&msg.data, &msg.audience);
+    }
+}
+
+/// Handler for RemovePosts message.
+impl Handler<RemovePosts> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: RemovePosts, _: &mut Context<Self>) {
+        self.send_invalid(&msg.posts);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix::SystemRunner;
+    use diesel::{prelude::*, r2d2::ConnectionManager};
+    use futures::Future;
+    use r2d2::Pool;
+
+    /// Keeps the messages the chat server sends it
+    #[derive(Default)]
+    struct Client {
+        received: Vec<Vec<u8>>,
+        closed: bool,
+    }
+
+    impl Actor for Client {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<ServerMessage> for Client {
+        type Result = ();
+
+        fn handle(&mut self, msg: ServerMessage, _: &mut Context<Self>) {
+            self.received.push(msg.0);
+        }
+    }
+
+    impl Handler<Close> for Client {
+        type Result = ();
+
+        fn handle(&mut self, _: Close, _: &mut Context<Self>) {
+            self.closed = true;
+        }
+    }
+
+    /// Ask a client for the messages it received so far
+    struct Received;
+
+    /// Ask a client whether the chat server closed it
+    struct Closed;
+
+    impl Message for Closed {
+        type Result = bool;
+    }
+
+    impl Handler<Closed> for Client {
+        type Result = MessageResult<Closed>;
+
+        fn handle(&mut self, _: Closed, _: &mut Context<Self>) -> Self::Result {
+            MessageResult(self.closed)
+        }
+    }
+
+    /// Ask the chat server for its `ChatMetrics`
+    struct Metrics;
+
+    impl Message for Metrics {
+        type Result = ChatMetrics;
+    }
+
+    impl Handler<Metrics> for ChatServer {
+        type Result = MessageResult<Metrics>;
+
+        fn handle(&mut self, _: Metrics, _: &mut Context<Self>) -> Self::Result {
+            MessageResult(self.metrics())
+        }
+    }
+
+    impl Message for Received {
+        type Result = Vec<Vec<u8>>;
+    }
+
+    impl Handler<Received> for Client {
+        type Result = MessageResult<Received>;
+
+        fn handle(&mut self, _: Received, _: &mut Context<Self>) -> Self::Result {
+            MessageResult(self.received.clone())
+        }
+    }
+
+    /// A chat server whose database is never reached
+    fn chat_server() -> ChatServer {
+        let manager = ConnectionManager::<PgConnection>::new("postgres://localhost/unused");
+        let pool = Pool::builder().min_idle(Some(0)).build_unchecked(manager);
+        let db = SyncArbiter::start(1, move || DbExecutor(pool.clone()));
+        ChatServer::new(db)
+    }
+
+    fn rooms(ids: &[i32]) -> HashSet<i32> {
+        ids.iter().cloned().collect()
+    }
+
+    fn session(client: &Addr<Client>, user_id: i32, joined: &[i32]) -> Session {
+        Session::new(
+            client.clone().recipient(),
+            client.clone().recipient(),
+            user_id,
+            rooms(joined),
+        )
+    }
+
+    /// What a client received once the chat server handled every message sent
+    /// to it before
+    fn received(
+        sys: &mut SystemRunner,
+        chat: &Addr<ChatServer>,
+        client: &Addr<Client>,
+    ) -> Vec<Vec<u8>> {
+        let client = client.clone();
+        sys.block_on(chat.send(Metrics).and_then(move |_| client.send(Received)))
+            .unwrap()
+    }
+
+    fn connect(
+        sys: &mut SystemRunner,
+        chat: &Addr<ChatServer>,
+        client: &Addr<Client>,
+        joined: &[i32],
+    ) -> String {
+        sys.block_on(chat.send(Connect {
+            addr: client.clone().recipient(),
+            close: client.clone().recipient(),
+            user_id: 1,
+            rooms: rooms(joined),
+        })).unwrap()
+    }
+
+    fn send_to_room(chat: &Addr<ChatServer>, room_id: i32) {
+        chat.do_send(SendTo {
+            audience: Audience::Room(room_id),
+            data: vec![room_id as u8],
+        });
+    }
+
+    #[test]
+    fn connecting_again_keeps_the_rooms() {
+        let mut sys = System::new("chat");
+        let chat = chat_server().start();
+        let client = Client::default().start();
+
+        let id = connect(&mut sys, &chat, &client, &[GENERAL_ROOM]);
+        chat.do_send(JoinRoom {
+            id: id.clone(),
+            room_id: 5,
+        });
+
+        // Logging in again on the same connection
+        let again = connect(&mut sys, &chat, &client, &[GENERAL_ROOM, 5]);
+        assert_eq!(id, again);
+
+        send_to_room(&chat, 5);
+        assert_eq!(vec![vec![5]], received(&mut sys, &chat, &client));
+    }
+
+    #[test]
+    fn evicted_sessions_rejoin_their_rooms() {
+        let mut sys = System::new("chat");
+        let chat = chat_server().start();
+        let client = Client::default().start();
+
+        let id = connect(&mut sys, &chat, &client, &[GENERAL_ROOM, 5]);
+        chat.do_send(Disconnect { id: id.clone() });
+        send_to_room(&chat, 5);
+        assert!(received(&mut sys, &chat, &client).is_empty());
+
+        chat.do_send(Heartbeat {
+            id,
+            addr: client.clone().recipient(),
+            close: client.clone().recipient(),
+            user_id: 1,
+            rooms: rooms(&[GENERAL_ROOM, 5]),
+        });
+        send_to_room(&chat, 5);
+        assert_eq!(vec![vec![5]], received(&mut sys, &chat, &client));
+    }
+
+    #[test]
+    fn messages_reach_their_audience() {
+        let mut sys = System::new("chat");
+        let mut chat = chat_server();
+        let clients: Vec<Addr<Client>> = (0..3).map(|_| Client::default().start()).collect();
+        // Two devices of user 1 and one of user 2
+        chat.insert_session("a1".to_string(), session(&clients[0], 1, &[GENERAL_ROOM, 5]));
+        chat.insert_session("a2".to_string(), session(&clients[1], 1, &[GENERAL_ROOM, 6]));
+        chat.insert_session("b".to_string(), session(&clients[2], 2, &[GENERAL_ROOM, 5]));
+
+        let audiences = [
+            (Audience::All, [true, true, true]),
+            (Audience::Session("a2".to_string()), [false, true, false]),
+            (Audience::Session("gone".to_string()), [false, false, false]),
+            (Audience::User(1), [true, true, false]),
+            (Audience::AllExceptSession("a1".to_string()), [false, true, true]),
+            (Audience::AllExceptUser(1), [false, false, true]),
+            (Audience::Room(5), [true, false, true]),
+            (Audience::RoomExceptSession(5, "b".to_string()), [true, false, false]),
+        ];
+        for (i, &(ref audience, _)) in audiences.iter().enumerate() {
+            chat.send_message(&[i as u8], audience);
+        }
+
+        for (c, client) in clients.iter().enumerate() {
+            let expected: Vec<Vec<u8>> = audiences
+                .iter()
+                .enumerate()
+                .filter(|&(_, &(_, reached))| reached[c])
+                .map(|(i, _)| vec![i as u8])
+                .collect();
+            assert_eq!(expected, sys.block_on(client.send(Received)).unwrap());
+        }
+    }
+
+    #[test]
+    fn sessions_of_stopped_actors_are_evicted() {
+        let mut sys = System::new("chat");
+        let mut chat = chat_server();
+        let alive = Client::default().start();
+        let stopped = Client::create(|ctx| {
+            ctx.stop();
+            Client::default()
+        });
+        // Let the stopped actor wind down
+        sys.block_on(alive.send(Received)).unwrap();
+
+        chat.insert_session("alive".to_string(), session(&alive, 1, &[GENERAL_ROOM]));
+        chat.insert_session("stopped".to_string(), session(&stopped, 2, &[GENERAL_ROOM]));
+        chat.send_message(&[1], &Audience::All);
+
+        assert_eq!(vec![vec![1]], sys.block_on(alive.send(Received)).unwrap());
+        assert_eq!(
+            ChatMetrics {
+                connected: 1,
+                evicted_closed: 1,
+                evicted_timeout: 0,
+            },
+            chat.metrics()
+        );
+        assert!(!chat.users.contains_key(&2));
+    }
+
+    #[test]
+    fn sessions_without_heartbeats_are_evicted() {
+        let _sys = System::new("chat");
+        let mut chat = chat_server();
+        for &(id, user_id) in &[("lapsed", 1), ("responsive", 1), ("other", 2)] {
+            let client = Client::default().start();
+            chat.insert_session(id.to_string(), session(&client, user_id, &[GENERAL_ROOM]));
+        }
+        let lapsed_at = Instant::now() - chat.session_timeout * 2;
+        chat.sessions.get_mut("lapsed").unwrap().last_heartbeat = lapsed_at;
+        chat.sessions.get_mut("other").unwrap().last_heartbeat = lapsed_at;
+
+        chat.evict_lapsed();
+
+        assert_eq!(
+            ChatMetrics {
+                connected: 1,
+                evicted_closed: 0,
+                evicted_timeout: 2,
+            },
+            chat.metrics()
+        );
+        assert!(chat.sessions.contains_key("responsive"));
+        assert_eq!(Some(1), chat.users.get(&1).map(|ids| ids.len()));
+        assert!(!chat.users.contains_key(&2));
+    }
+
+    #[test]
+    fn disconnecting_a_user_closes_their_sessions() {
+        let mut sys = System::new("chat");
+        let mut chat = chat_server();
+        let clients: Vec<Addr<Client>> = (0..3).map(|_| Client::default().start()).collect();
+        chat.insert_session("a1".to_string(), session(&clients[0], 1, &[GENERAL_ROOM]));
+        chat.insert_session("a2".to_string(), session(&clients[1], 1, &[GENERAL_ROOM]));
+        chat.insert_session("b".to_string(), session(&clients[2], 2, &[GENERAL_ROOM]));
+        let chat = chat.start();
+
+        chat.do_send(DisconnectUser { user_id: 1 });
+        send_to_room(&chat, GENERAL_ROOM);
+
+        let expected = [(true, false), (true, false), (false, true)];
+        for (client, &(closed, reached)) in clients.iter().zip(&expected) {
+            assert_eq!(reached, !received(&mut sys, &chat, client).is_empty());
+            assert_eq!(closed, sys.block_on(client.send(Closed)).unwrap());
+        }
     }
 }
@@ -0,0 +1,12 @@
+//! Helpers for reading settings from the environment
+
+use std::{env, str::FromStr};
+
+/// Read a setting from the environment, falling back to `default` if it is
+/// not set or cannot be parsed
+pub fn var_or<T: FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
@@ -1,17 +1,22 @@
 use actix::prelude::*;
 use actix_web::*;
-use bcrypt::{self, DEFAULT_COST};
 use diesel::{
     self,
-    dsl::{now, IntervalDsl},
-    pg::expression::dsl::any,
+    dsl::{exists, now, IntervalDsl},
+    pg::{expression::dsl::any, Pg},
     prelude::*,
     r2d2::{ConnectionManager, Pool},
+    result::{DatabaseErrorKind, Error as DieselError},
 };
 use failure::Error;
-use std::cmp::Ordering;
-
-use super::models::{NewPost, NewUser, Post, Session, User, Vote};
+use std::{cmp::Ordering, time::SystemTime};
+use uuid::Uuid;
+
+use super::models::{NewPost, NewRoom, NewSession, NewUser, Post, Room, Session, User, Vote};
+use pagination::{Cursor, Order};
+use session::ClientInfo;
+use token::{hash_refresh_token, new_refresh_token, refresh_token_expiry, SessionTokens, Token};
+use validation;
 use ServerError;
 
 pub struct DbExecutor(pub Pool<ConnectionManager<PgConnection>>);
@@ -21,43 +26,78 @@ impl Actor for DbExecutor {
 }
 
 pub struct CreateSession {
-    pub id: String,
+    pub user_id: i32,
+    pub client: ClientInfo,
 }
 
 impl Message for CreateSession {
-    type Result = Result<Session, Error>;
+    type Result = Result<SessionTokens, Error>;
 }
 
 impl Handler<CreateSession> for DbExecutor {
-    type Result = Result<Session, Error>;
+    type Result = Result<SessionTokens, Error>;
 
     fn handle(&mut self, msg: CreateSession, _: &mut Self::Context) -> Self::Result {
         use super::schema::sessions::dsl::*;
-        diesel::insert_into(sessions)
-            .values(&Session { id: msg.id })
-            .get_result::<Session>(&self.0.get()?)
-            .map_err(|_| ServerError::InsertToken.into())
+        let refresh = new_refresh_token()?;
+        let session = diesel::insert_into(sessions)
+            .values(&NewSession {
+                id: Uuid::new_v4().to_string(),
+                user_id: msg.user_id,
+                refresh_token: hash_refresh_token(&refresh),
+                expires_at: refresh_token_expiry(),
+                created_at: SystemTime::now(),
+                last_seen_at: SystemTime::now(),
+                user_agent: msg.client.user_agent,
+                ip_address: msg.client.ip_address,
+            }).get_result::<Session>(&self.0.get()?)
+            .map_err(|_| ServerError::InsertToken)?;
+
+        Ok(SessionTokens {
+            access: Token::create(session.user_id, &session.id)?,
+            refresh,
+        })
     }
 }
 
-pub struct UpdateSession {
-    pub old_id: String,
-    pub new_id: String,
+/// Trade a refresh token for a new access and refresh token pair
+///
+/// The old refresh token stops working once it has been used.
+pub struct RefreshSession {
+    pub refresh_token: String,
+    pub client: ClientInfo,
 }
 
-impl Message for UpdateSession {
-    type Result = Result<Session, Error>;
+impl Message for RefreshSession {
+    type Result = Result<(SessionTokens, i32), Error>;
 }
 
-impl Handler<UpdateSession> for DbExecutor {
-    type Result = Result<Session, Error>;
+impl Handler<RefreshSession> for DbExecutor {
+    type Result = Result<(SessionTokens, i32), Error>;
 
-    fn handle(&mut self, msg: UpdateSession, _: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: RefreshSession, _: &mut Self::Context) -> Self::Result {
         use super::schema::sessions::dsl::*;
-        diesel::update(sessions.filter(id.eq(&msg.old_id)))
-            .set(id.eq(&msg.new_id))
-            .get_result::<Session>(&self.0.get()?)
-            .map_err(|_| ServerError::UpdateToken.into())
+        let refresh = new_refresh_token()?;
+        let session = diesel::update(
+            sessions
+                .filter(refresh_token.eq(hash_refresh_token(&msg.refresh_token)))
+                .filter(expires_at.gt(SystemTime::now())),
+        ).set((
+            refresh_token.eq(hash_refresh_token(&refresh)),
+            expires_at.eq(refresh_token_expiry()),
+            last_seen_at.eq(SystemTime::now()),
+            user_agent.eq(msg.client.user_agent),
+            ip_address.eq(msg.client.ip_address),
+        )).get_result::<Session>(&self.0.get()?)
+        .optional()
+        .map_err(|_| ServerError::UpdateToken)?
+        .ok_or(ServerError::VerifyToken)?;
+
+        let tokens = SessionTokens {
+            access: Token::create(session.user_id, &session.id)?,
+            refresh,
+        };
+        Ok((tokens, session.user_id))
     }
 }
 
@@ -81,9 +121,147 @@ impl Handler<DeleteSession> for DbExecutor {
     }
 }
 
+/// Look up a session that has not expired yet and record that it was seen
+pub struct FindSession {
+    pub session_id: String,
+}
+
+impl Message for FindSession {
+    type Result = Result<Option<Session>, Error>;
+}
+
+impl Handler<FindSession> for DbExecutor {
+    type Result = Result<Option<Session>, Error>;
+
+    fn handle(&mut self, msg: FindSession, _: &mut Self::Context) -> Self::Result {
+        use super::schema::sessions::dsl::*;
+        diesel::update(
+            sessions
+                .filter(id.eq(&msg.session_id))
+                .filter(expires_at.gt(SystemTime::now())),
+        ).set(last_seen_at.eq(SystemTime::now()))
+        .get_result::<Session>(&self.0.get()?)
+        .optional()
+        .map_err(|_| ServerError::InvalidSession.into())
+    }
+}
+
+/// Delete every session of a user, returning how many were removed
+pub struct DeleteUserSessions {
+    pub user_id: i32,
+}
+
+impl Message for DeleteUserSessions {
+    type Result = Result<usize, Error>;
+}
+
+impl Handler<DeleteUserSessions> for DbExecutor {
+    type Result = Result<usize, Error>;
+
+    fn handle(&mut self, msg: DeleteUserSessions, _: &mut Self::Context) -> Self::Result {
+        use super::schema::sessions::dsl::*;
+        diesel::delete(sessions.filter(user_id.eq(msg.user_id)))
+            .execute(&self.0.get()?)
+            .map_err(|_| ServerError::RemoveToken.into())
+    }
+}
+
+/// List the sessions of a user that have not expired, most recently used first
+pub struct ListSessions {
+    pub user_id: i32,
+}
+
+impl Message for ListSessions {
+    type Result = Result<Vec<Session>, Error>;
+}
+
+impl Handler<ListSessions> for DbExecutor {
+    type Result = Result<Vec<Session>, Error>;
+
+    fn handle(&mut self, msg: ListSessions, _: &mut Self::Context) -> Self::Result {
+        use super::schema::sessions::dsl::*;
+        sessions
+            .filter(user_id.eq(msg.user_id))
+            .filter(expires_at.gt(SystemTime::now()))
+            .order(last_seen_at.desc())
+            .load::<Session>(&self.0.get()?)
+            .map_err(|_| ServerError::FindSessions.into())
+    }
+}
+
+/// End one session of a user
+///
+/// Only sessions belonging to `user_id` can be revoked.
+pub struct RevokeSession {
+    pub user_id: i32,
+    pub session_id: String,
+}
+
+impl Message for RevokeSession {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<RevokeSession> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: RevokeSession, _: &mut Self::Context) -> Self::Result {
+        use super::schema::sessions::dsl::*;
+        let deleted = diesel::delete(
+            sessions
+                .filter(id.eq(&msg.session_id))
+                .filter(user_id.eq(msg.user_id)),
+        ).execute(&self.0.get()?)
+        .map_err(|_| ServerError::RemoveToken)?;
+
+        match deleted {
+            0 => Err(ServerError::InvalidSession.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Delete at most `batch_size` expired sessions, returning how many were
+///
+/// Deleting in batches keeps every statement short, so a large backlog of
+/// expired sessions does not hold locks on the table for long. The
+/// `SessionSweeper` sends one message per batch, letting other queries run on
+/// this executor in between.
+pub struct PurgeExpiredSessions {
+    pub batch_size: i64,
+}
+
+impl Message for PurgeExpiredSessions {
+    type Result = Result<usize, Error>;
+}
+
+impl Handler<PurgeExpiredSessions> for DbExecutor {
+    type Result = Result<usize, Error>;
+
+    fn handle(&mut self, msg: PurgeExpiredSessions, _: &mut Self::Context) -> Self::Result {
+        use super::schema::sessions::dsl::*;
+        let conn = self.0.get()?;
+
+        let expired = sessions
+            .select(id)
+            .filter(expires_at.lt(SystemTime::now()))
+            .limit(msg.batch_size)
+            .load::<String>(&conn)
+            .map_err(|_| ServerError::RemoveToken)?;
+
+        if expired.is_empty() {
+            return Ok(0);
+        }
+
+        Ok(diesel::delete(sessions.filter(id.eq(any(&expired))))
+            .execute(&conn)
+            .map_err(|_| ServerError::RemoveToken)?)
+    }
+}
+
+/// Add a user whose password was already hashed by the `Hasher`
 pub struct CreateUser {
     pub username: String,
-    pub password: String,
+    pub password_hash: String,
 }
 
 impl Message for CreateUser {
@@ -97,16 +275,22 @@ impl Handler<CreateUser> for DbExecutor {
         use super::schema::users::dsl::*;
         diesel::insert_into(users)
             .values(&NewUser {
+                username_key: validation::username_key(&msg.username),
                 username: msg.username,
-                password: bcrypt::hash(&msg.password, DEFAULT_COST)?,
+                password: msg.password_hash,
             }).get_result::<User>(&self.0.get()?)
-            .map_err(|_| ServerError::CreateUser.into())
+            .map_err(|e| match e {
+                DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _) => {
+                    ServerError::UsernameTaken.into()
+                }
+                _ => ServerError::CreateUser.into(),
+            })
     }
 }
 
+/// Look a user up by name, the password is checked by the `Hasher`
 pub struct FindUser {
     pub username: String,
-    pub password: String,
 }
 
 impl Message for FindUser {
@@ -118,23 +302,117 @@ impl Handler<FindUser> for DbExecutor {
 
     fn handle(&mut self, msg: FindUser, _: &mut Self::Context) -> Self::Result {
         use super::schema::users::dsl::*;
-        let user = users
-            .filter(username.eq(msg.username))
-            .select((id, username, password, karma, streak))
+        Ok(users
+            .filter(username_key.eq(validation::username_key(&msg.username)))
+            .select((id, username, password, karma, streak, username_key))
             .first::<User>(&self.0.get()?)
             .optional()
-            .map_err(|_| ServerError::FindUser)?;
+            .map_err(|_| ServerError::FindUser)?)
+    }
+}
 
-        match user {
-            Some(u) => {
-                if bcrypt::verify(&msg.password, &u.password)? {
-                    Ok(Some(u))
-                } else {
-                    Err(ServerError::IncorrectPassword.into())
-                }
+/// Replace a password hash made with an outdated scheme
+///
+/// Nothing is changed if the password was changed in the meantime.
+pub struct UpdatePasswordHash {
+    pub user_id: i32,
+    pub old_hash: String,
+    pub new_hash: String,
+}
+
+impl Message for UpdatePasswordHash {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<UpdatePasswordHash> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: UpdatePasswordHash, _: &mut Self::Context) -> Self::Result {
+        use super::schema::users::dsl::*;
+        diesel::update(
+            users
+                .filter(id.eq(msg.user_id))
+                .filter(password.eq(msg.old_hash)),
+        ).set(password.eq(msg.new_hash))
+        .execute(&self.0.get()?)?;
+
+        Ok(())
+    }
+}
+
+/// Set a new password and end every other session of the user
+pub struct ChangePassword {
+    pub user_id: i32,
+    /// The session the change was made from, it stays valid
+    pub session_id: String,
+    pub password_hash: String,
+}
+
+impl Message for ChangePassword {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<ChangePassword> for DbExecutor {
+    type Result = Result<(), Error>;
+
+    fn handle(&mut self, msg: ChangePassword, _: &mut Self::Context) -> Self::Result {
+        let conn = self.0.get()?;
+        conn.transaction::<_, Error, _>(|| {
+            {
+                use super::schema::users::dsl::*;
+                diesel::update(users.find(msg.user_id))
+                    .set(password.eq(&msg.password_hash))
+                    .execute(&conn)?;
             }
-            None => Ok(None),
-        }
+
+            use super::schema::sessions::dsl::*;
+            diesel::delete(
+                sessions
+                    .filter(user_id.eq(msg.user_id))
+                    .filter(id.ne(&msg.session_id)),
+            ).execute(&conn)?;
+
+            Ok(())
+        })
+    }
+}
+
+/// Delete a user together with their sessions, posts and votes
+///
+/// Returns the posts that were still open, so clients can be told to drop
+/// them.
+pub struct DeleteUser {
+    pub user_id: i32,
+}
+
+impl Message for DeleteUser {
+    type Result = Result<Vec<Post>, Error>;
+}
+
+impl Handler<DeleteUser> for DbExecutor {
+    type Result = Result<Vec<Post>, Error>;
+
+    fn handle(&mut self, msg: DeleteUser, _: &mut Self::Context) -> Self::Result {
+        let conn = self.0.get()?;
+        conn.transaction::<_, Error, _>(|| {
+            let open_posts = {
+                use super::schema::posts::dsl::*;
+                posts
+                    .filter(user_id.eq(msg.user_id))
+                    .filter(valid.eq(true))
+                    .load::<Post>(&conn)?
+            };
+
+            // Sessions, posts and votes go with it, see the account_deletion
+            // migration
+            use super::schema::users::dsl::*;
+            let deleted = diesel::delete(users.find(msg.user_id)).execute(&conn)?;
+            if deleted == 0 {
+                return Err(ServerError::FindUser.into());
+            }
+
+            Ok(open_posts)
+        })
     }
 }
 
@@ -153,7 +431,7 @@ impl Handler<FindUserID> for DbExecutor {
         use super::schema::users::dsl::*;
         let user = users
             .filter(id.eq(msg.user_id))
-            .select((id, username, password, karma, streak))
+            .select((id, username, password, karma, streak, username_key))
             .first::<User>(&self.0.get()?)
             .optional()
             .map_err(|_| ServerError::FindUser)?;
@@ -168,6 +446,7 @@ impl Handler<FindUserID> for DbExecutor {
 pub struct CreatePost {
     pub content: String,
     pub user_id: i32,
+    pub room_id: i32,
 }
 
 impl Message for CreatePost {
@@ -179,100 +458,249 @@ impl Handler<CreatePost> for DbExecutor {
 
     fn handle(&mut self, msg: CreatePost, _: &mut Self::Context) -> Self::Result {
         let conn = self.0.get()?;
-        let post = {
-            use super::schema::posts::dsl::*;
-            diesel::insert_into(posts)
-                .values(&NewPost {
-                    content: msg.content.clone(),
-                    user_id: msg.user_id,
-                }).get_result::<Post>(&conn)
-                .map_err(|_| ServerError::InsertPost)?
-        };
+        conn.transaction::<_, Error, _>(|| {
+            {
+                use super::schema::rooms::dsl::*;
+                let found = diesel::select(exists(rooms.filter(id.eq(msg.room_id))))
+                    .get_result::<bool>(&conn)
+                    .map_err(|_| ServerError::InsertPost)?;
+                if !found {
+                    return Err(ServerError::RoomNotFound.into());
+                }
+            }
 
-        let name = {
-            use super::schema::users::dsl::*;
-            users
-                .filter(id.eq(msg.user_id))
-                .select(username)
-                .first::<String>(&self.0.get()?)
-                .map_err(|_| ServerError::FindUser)?
-        };
+            let post = {
+                use super::schema::posts::dsl::*;
+                diesel::insert_into(posts)
+                    .values(&NewPost {
+                        content: msg.content.clone(),
+                        user_id: msg.user_id,
+                        room_id: msg.room_id,
+                    }).get_result::<Post>(&conn)
+                    .map_err(|_| ServerError::InsertPost)?
+            };
+
+            let name = {
+                use super::schema::users::dsl::*;
+                users
+                    .filter(id.eq(msg.user_id))
+                    .select(username)
+                    .first::<String>(&conn)
+                    .map_err(|_| ServerError::FindUser)?
+            };
 
-        Ok((post, name))
+            Ok((post, name))
+        })
     }
 }
 
+/// A page of the valid posts of a room with the votes the user cast on them
 pub struct FetchPosts {
     pub user_id: i32,
+    pub room_id: i32,
+    /// Where the previous page ended, `None` for the first page
+    pub after: Option<Cursor>,
+    pub limit: u16,
+    pub order: Order,
+}
+
+pub struct PostPage {
+    pub posts: Vec<(Post, Option<Vote>)>,
+    /// Where the next page starts, `None` if this is the last one
+    pub next: Option<Cursor>,
 }
 
 impl Message for FetchPosts {
-    type Result = Result<Vec<(Post, Option<Vote>)>, Error>;
+    type Result = Result<PostPage, Error>;
 }
 
 impl Handler<FetchPosts> for DbExecutor {
-    type Result = Result<Vec<(Post, Option<Vote>)>, Error>;
+    type Result = Result<PostPage, Error>;
 
     fn handle(&mut self, msg: FetchPosts, _: &mut Self::Context) -> Self::Result {
-        let conn = self.0.get()?;
-        let posts_lists: Vec<Post> = {
-            use super::schema::posts::dsl::*;
-            posts.filter(valid.eq(true)).load::<Post>(&conn)?
+        fetch_posts(&*self.0.get()?, &msg)
+    }
+}
+
+/// Load a page of posts and the user's votes on them in two queries
+///
+/// Kept apart from the handler so it can be run on any connection, which
+/// lets tests count the queries it makes.
+pub fn fetch_posts<C>(conn: &C, msg: &FetchPosts) -> Result<PostPage, Error>
+where
+    C: Connection<Backend = Pg>,
+{
+    let (page, next) = {
+        use super::schema::posts::dsl::*;
+        let mut query = posts
+            .filter(valid.eq(true))
+            .filter(room_id.eq(msg.room_id))
+            .into_boxed();
+
+        query = match msg.order {
+            Order::NewestFirst => query.order((created_at.desc(), id.desc())),
+            Order::OldestFirst => query.order((created_at.asc(), id.asc())),
         };
 
-        // let votes_lists: Vec<Vec<Vote>> = {
-        //     use super::schema::votes::dsl::*;
-        //     Vote::belonging_to(&posts_lists)
-        //         .filter(user_id.eq(msg.user_id))
-        //         .load::<Vote>(&conn)?
-        //         .grouped_by(&posts_lists)
-        // };
-        {
-            use super::schema::votes::dsl::*;
-            Ok(posts_lists
-                .into_iter()
-                .map(|post| {
-                    let res = votes
-                        .filter(user_id.eq(msg.user_id))
-                        .filter(post_id.eq(post.id))
-                        .first::<Vote>(&conn)
-                        .optional();
-                    (post, res.unwrap_or(None))
-                }).collect::<Vec<_>>())
+        if let Some(cursor) = msg.after {
+            query = match msg.order {
+                Order::NewestFirst => query.filter(
+                    created_at
+                        .lt(cursor.created_at)
+                        .or(created_at.eq(cursor.created_at).and(id.lt(cursor.id))),
+                ),
+                Order::OldestFirst => query.filter(
+                    created_at
+                        .gt(cursor.created_at)
+                        .or(created_at.eq(cursor.created_at).and(id.gt(cursor.id))),
+                ),
+            };
         }
+
+        // One post more than asked for tells whether there is a next page
+        let limit = usize::from(msg.limit);
+        let mut page = query.limit(limit as i64 + 1).load::<Post>(conn)?;
+        let next = if page.len() > limit {
+            page.truncate(limit);
+            page.last().map(Cursor::after)
+        } else {
+            None
+        };
+        (page, next)
+    };
+
+    let votes_lists: Vec<Vec<Vote>> = {
+        use super::schema::votes::dsl::*;
+        Vote::belonging_to(&page)
+            .filter(user_id.eq(msg.user_id))
+            .load::<Vote>(conn)?
+            .grouped_by(&page)
+    };
+
+    // A user casts at most one vote on a post
+    let posts = page
+        .into_iter()
+        .zip(votes_lists)
+        .map(|(post, mut votes)| (post, votes.pop()))
+        .collect();
+
+    Ok(PostPage { posts, next })
+}
+
+/// Find a room by name, creating it if there is none
+pub struct FindOrCreateRoom {
+    pub name: String,
+}
+
+impl Message for FindOrCreateRoom {
+    type Result = Result<Room, Error>;
+}
+
+impl Handler<FindOrCreateRoom> for DbExecutor {
+    type Result = Result<Room, Error>;
+
+    fn handle(&mut self, msg: FindOrCreateRoom, _: &mut Self::Context) -> Self::Result {
+        use super::schema::rooms::dsl::*;
+        let conn = self.0.get()?;
+
+        diesel::insert_into(rooms)
+            .values(&NewRoom {
+                name: msg.name.clone(),
+            }).on_conflict(name)
+            .do_nothing()
+            .execute(&conn)?;
+
+        Ok(rooms.filter(name.eq(&msg.name)).first::<Room>(&conn)?)
     }
 }
 
+pub struct ListRooms;
+
+impl Message for ListRooms {
+    type Result = Result<Vec<Room>, Error>;
+}
+
+impl Handler<ListRooms> for DbExecutor {
+    type Result = Result<Vec<Room>, Error>;
+
+    fn handle(&mut self, _msg: ListRooms, _: &mut Self::Context) -> Self::Result {
+        use super::schema::rooms::dsl::*;
+        Ok(rooms.order(id).load::<Room>(&self.0.get()?)?)
+    }
+}
+
+/// Cast, change or withdraw a vote, returning the post voted on with its new
+/// tally
+///
+/// Only posts still open and made by someone else can be voted on. The post
+/// is locked while the vote is cast, so it cannot close in the meantime.
 pub struct UserVote {
     pub post_id: i32,
     pub user_id: i32,
-    // 1 will represent an upvote, -1 will represent a downvote
+    // 1 will represent an upvote, -1 will represent a downvote and 0
+    // withdraws the vote
     pub up_or_down: i16,
 }
 
 impl Message for UserVote {
-    type Result = Result<(), Error>;
+    type Result = Result<Post, Error>;
 }
 
 impl Handler<UserVote> for DbExecutor {
-    type Result = Result<(), Error>;
+    type Result = Result<Post, Error>;
 
     fn handle(&mut self, msg: UserVote, _: &mut Self::Context) -> Self::Result {
-        use super::schema::votes::dsl::*;
-        diesel::insert_into(votes)
-            .values(&Vote {
-                post_id: msg.post_id,
-                user_id: msg.user_id,
-                up_or_down: msg.up_or_down,
-            }).on_conflict((post_id, user_id))
-            .do_update()
-            .set(up_or_down.eq(msg.up_or_down))
-            .execute(&self.0.get()?)
-            .map_err(|_| ServerError::InsertVote.into())
-            .map(|_| ())
+        user_vote(&self.0.get()?, &msg)
     }
 }
 
+/// Cast a vote in a transaction of its own
+///
+/// Kept apart from the handler so tests can run it inside a transaction that
+/// is rolled back.
+pub fn user_vote(conn: &PgConnection, msg: &UserVote) -> Result<Post, Error> {
+    conn.transaction::<_, Error, _>(|| {
+        let post = {
+            use super::schema::posts::dsl::*;
+            posts
+                .find(msg.post_id)
+                .for_update()
+                .first::<Post>(conn)
+                .optional()?
+                .ok_or(ServerError::PostNotFound)?
+        };
+
+        if !post.valid {
+            return Err(ServerError::PostClosed.into());
+        }
+        if post.user_id == msg.user_id {
+            return Err(ServerError::SelfVote.into());
+        }
+
+        {
+            use super::schema::votes::dsl::*;
+            if msg.up_or_down == 0 {
+                diesel::delete(votes.find((msg.user_id, msg.post_id))).execute(conn)?;
+            } else {
+                diesel::insert_into(votes)
+                    .values(&Vote {
+                        post_id: msg.post_id,
+                        user_id: msg.user_id,
+                        up_or_down: msg.up_or_down,
+                    }).on_conflict((post_id, user_id))
+                    .do_update()
+                    .set(up_or_down.eq(msg.up_or_down))
+                    .execute(conn)
+                    .map_err(|_| ServerError::InsertVote)?;
+            }
+        }
+
+        // Read again for the tally the vote changed
+        use super::schema::posts::dsl::*;
+        Ok(posts.find(msg.post_id).first::<Post>(conn)?)
+    })
+}
+
 pub struct UpdateKarma;
 
 impl Message for UpdateKarma {
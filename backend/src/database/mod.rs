@@ -1,5 +1,6 @@
 pub mod executor;
 pub mod models;
 pub mod schema;
+pub mod username_keys;
 
 pub use self::executor::DbExecutor;
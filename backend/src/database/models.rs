@@ -1,10 +1,30 @@
-use super::schema::{posts, sessions, users, votes};
+use super::schema::{posts, rooms, sessions, users, votes};
 use std::time::SystemTime;
 
-#[derive(Queryable, Insertable)]
+#[derive(Insertable)]
 #[table_name = "sessions"]
+pub struct NewSession {
+    pub id: String,
+    pub user_id: i32,
+    /// Hash of the refresh token, the token itself is never stored
+    pub refresh_token: String,
+    pub expires_at: SystemTime,
+    pub created_at: SystemTime,
+    pub last_seen_at: SystemTime,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+#[derive(Queryable, Debug)]
 pub struct Session {
     pub id: String,
+    pub user_id: i32,
+    pub refresh_token: String,
+    pub expires_at: SystemTime,
+    pub created_at: SystemTime,
+    pub last_seen_at: SystemTime,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
 }
 
 #[derive(Insertable)]
@@ -12,6 +32,7 @@ pub struct Session {
 pub struct NewUser {
     pub username: String,
     pub password: String,
+    pub username_key: String,
 }
 
 #[derive(Queryable, Debug)]
@@ -21,6 +42,7 @@ pub struct User {
     pub password: String,
     pub karma: i32,
     pub streak: i16,
+    pub username_key: String,
 }
 
 #[derive(Insertable)]
@@ -28,6 +50,7 @@ pub struct User {
 pub struct NewPost {
     pub content: String,
     pub user_id: i32,
+    pub room_id: i32,
 }
 
 #[derive(Queryable, Identifiable, Debug)]
@@ -37,6 +60,23 @@ pub struct Post {
     pub valid: bool,
     pub created_at: SystemTime,
     pub user_id: i32,
+    pub room_id: i32,
+    /// Kept up to date by the database as votes are cast
+    pub up_count: i32,
+    pub down_count: i32,
+}
+
+#[derive(Insertable)]
+#[table_name = "rooms"]
+pub struct NewRoom {
+    pub name: String,
+}
+
+#[derive(Queryable, Debug)]
+pub struct Room {
+    pub id: i32,
+    pub name: String,
+    pub created_at: SystemTime,
 }
 
 #[derive(Queryable, Insertable, Identifiable, Associations, Debug)]
@@ -5,12 +5,30 @@ table! {
         valid -> Bool,
         created_at -> Timestamp,
         user_id -> Int4,
+        room_id -> Int4,
+        up_count -> Int4,
+        down_count -> Int4,
+    }
+}
+
+table! {
+    rooms (id) {
+        id -> Int4,
+        name -> Text,
+        created_at -> Timestamp,
     }
 }
 
 table! {
     sessions (id) {
         id -> Text,
+        user_id -> Int4,
+        refresh_token -> Text,
+        expires_at -> Timestamp,
+        created_at -> Timestamp,
+        last_seen_at -> Timestamp,
+        user_agent -> Nullable<Text>,
+        ip_address -> Nullable<Text>,
     }
 }
 
@@ -21,6 +39,7 @@ table! {
         password -> Text,
         karma -> Int4,
         streak -> Int2,
+        username_key -> Text,
     }
 }
 
@@ -32,8 +51,10 @@ table! {
     }
 }
 
+joinable!(posts -> rooms (room_id));
 joinable!(posts -> users (user_id));
+joinable!(sessions -> users (user_id));
 joinable!(votes -> posts (post_id));
 joinable!(votes -> users (user_id));
 
-allow_tables_to_appear_in_same_query!(posts, sessions, users, votes,);
+allow_tables_to_appear_in_same_query!(posts, rooms, sessions, users, votes,);
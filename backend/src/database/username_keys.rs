@@ -0,0 +1,153 @@
+//! Fills in the `username_key` of every user.
+//!
+//! The key is computed by `validation::username_key`, which normalizes to
+//! NFKC before case folding. SQL cannot do the same, so the migration adding
+//! the column leaves it empty and this runs right after the migrations, on
+//! every start, making the column NOT NULL and unique once the keys are set.
+//!
+//! Usernames registered before keys existed can clash once normalized, like
+//! `Steve` and `steve`. No account is renamed behind its owner's back: the
+//! backfill fails, naming the users that clash, and leaves everything as it
+//! was until they are renamed by hand.
+
+use diesel::{self, prelude::*};
+use failure::Error;
+use std::collections::HashMap;
+use validation;
+
+struct Row {
+    id: i32,
+    username: String,
+    username_key: Option<String>,
+}
+
+/// A user whose key has to be set
+#[derive(Debug, PartialEq)]
+struct Change {
+    id: i32,
+    key: String,
+}
+
+/// Set the keys that are missing or outdated and make sure they are unique,
+/// returning how many users changed
+pub fn backfill(conn: &PgConnection) -> Result<usize, Error> {
+    use super::schema::users::dsl::*;
+
+    conn.transaction(|| {
+        let rows = users
+            .select((id, username, username_key.nullable()))
+            .order(id)
+            .load::<(i32, String, Option<String>)>(conn)?
+            .into_iter()
+            .map(|(user_id, name, key)| Row {
+                id: user_id,
+                username: name,
+                username_key: key,
+            }).collect::<Vec<_>>();
+        let changes = changes(&rows)?;
+
+        if !changes.is_empty() {
+            // Keys can be swapped between users while fixing them up
+            conn.batch_execute(
+                "DROP INDEX IF EXISTS users_username_key_idx;
+                 ALTER TABLE users ALTER COLUMN username_key DROP NOT NULL;",
+            )?;
+            for change in &changes {
+                diesel::update(users.find(change.id))
+                    .set(username_key.eq(&change.key))
+                    .execute(conn)?;
+            }
+            info!("Backfilled the username keys of {} users", changes.len());
+        }
+        // Also on a fresh database, where there was nothing to backfill
+        conn.batch_execute(
+            "ALTER TABLE users ALTER COLUMN username_key SET NOT NULL;
+             CREATE UNIQUE INDEX IF NOT EXISTS users_username_key_idx ON users (username_key);",
+        )?;
+
+        Ok(changes.len())
+    })
+}
+
+/// The keys to set, failing if any two usernames share a key
+fn changes(rows: &[Row]) -> Result<Vec<Change>, Error> {
+    let mut by_key: HashMap<String, Vec<i32>> = HashMap::new();
+    let mut changes = Vec::new();
+
+    for row in rows {
+        let key = validation::username_key(&row.username);
+        if row.username_key.as_ref() != Some(&key) {
+            changes.push(Change {
+                id: row.id,
+                key: key.clone(),
+            });
+        }
+        by_key.entry(key).or_insert_with(Vec::new).push(row.id);
+    }
+
+    let mut clashes = by_key
+        .into_iter()
+        .map(|(_, ids)| ids)
+        .filter(|ids| ids.len() > 1)
+        .collect::<Vec<_>>();
+    if !clashes.is_empty() {
+        clashes.sort();
+        let clashes = clashes
+            .iter()
+            .map(|ids| {
+                let ids = ids.iter().map(i32::to_string).collect::<Vec<_>>();
+                ids.join(", ")
+            }).collect::<Vec<_>>();
+        bail!(
+            "Usernames differing only in case or normalization, rename all but one \
+             of each group of users: {}",
+            clashes.join("; ")
+        );
+    }
+
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(id: i32, username: &str, username_key: Option<&str>) -> Row {
+        Row {
+            id,
+            username: username.to_string(),
+            username_key: username_key.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn keys_are_normalized_before_case_folding() {
+        // The fullwidth `Ｓ` only folds to `s` after NFKC
+        let rows = [row(1, "Ｓteve", None), row(2, "Ann", Some("ann"))];
+        assert_eq!(
+            vec![Change {
+                id: 1,
+                key: "steve".to_string(),
+            }],
+            changes(&rows).unwrap()
+        );
+    }
+
+    #[test]
+    fn clashing_usernames_fail_the_backfill() {
+        let rows = [
+            row(1, "steve", Some("steve")),
+            row(2, "Ann", Some("ann")),
+            row(3, "ＳＴＥＶＥ", None),
+            row(4, "Steve", None),
+        ];
+        let error = changes(&rows).unwrap_err().to_string();
+        assert!(error.ends_with(": 1, 3, 4"), error);
+    }
+
+    #[test]
+    fn nothing_changes_once_keys_match() {
+        let rows = [row(1, "Steve", Some("steve")), row(2, "Ann", Some("ann"))];
+        assert!(changes(&rows).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,259 @@
+//! Password hashing on its own pool of threads.
+//!
+//! Password hashes are deliberately slow, so running them on the database
+//! threads would hold up every other query while someone logs in. `Hasher`
+//! actors run on a separate `SyncArbiter` sized with `HASHER_THREADS`.
+//!
+//! Argon2id hashes are stored as PHC strings (`$argon2id$v=19$m=...`). Bcrypt
+//! hashes stay in their modular crypt format (`$2b$12$...`) rather than the
+//! PHC form, since that is what hashes stored before Argon2id are in and what
+//! the bcrypt crate reads and writes. Both record the algorithm and its
+//! parameters. New hashes use the configured scheme, and a hash made with any
+//! other scheme is replaced the next time its user logs in.
+//!
+//! A login for an unknown user is checked against a dummy hash of the
+//! configured scheme, so it takes as long as a wrong password for a user whose
+//! hash is up to date. Users still holding a hash of another scheme take as
+//! long as that scheme does instead, which is measurably different: until
+//! they log in, timing tells their usernames apart from unknown ones. After
+//! changing `PASSWORD_HASH` or its parameters, reset the passwords of users
+//! that have not logged in for a while to close that gap.
+
+use super::config;
+use actix::prelude::*;
+use argon2::{self, ThreadMode, Variant, Version};
+use bcrypt::{self, DEFAULT_COST};
+use failure::Error;
+use openssl::rand::rand_bytes;
+
+/// The algorithm and parameters a hash is made with
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scheme {
+    Bcrypt {
+        cost: u32,
+    },
+    Argon2id {
+        /// Memory in KiB
+        memory: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+}
+
+impl Scheme {
+    /// The scheme new hashes are made with, Argon2id unless
+    /// `PASSWORD_HASH=bcrypt`
+    pub fn from_env() -> Self {
+        match config::var_or("PASSWORD_HASH", "argon2id".to_string()).as_str() {
+            "bcrypt" => Scheme::Bcrypt {
+                cost: config::var_or("BCRYPT_COST", DEFAULT_COST),
+            },
+            _ => Scheme::Argon2id {
+                memory: config::var_or("ARGON2_MEMORY", 19_456),
+                iterations: config::var_or("ARGON2_ITERATIONS", 2),
+                parallelism: config::var_or("ARGON2_PARALLELISM", 1),
+            },
+        }
+    }
+
+    /// Read the scheme of a stored hash
+    ///
+    /// Returns `None` for formats no hash should be made with any more.
+    pub fn of(hash: &str) -> Option<Self> {
+        let mut parts = hash.split('$').skip(1);
+        match parts.next()? {
+            "2a" | "2b" | "2x" | "2y" => Some(Scheme::Bcrypt {
+                cost: parts.next()?.parse().ok()?,
+            }),
+            "argon2id" => {
+                if parts.next()? != "v=19" {
+                    return None;
+                }
+
+                let (mut memory, mut iterations, mut parallelism) = (None, None, None);
+                for param in parts.next()?.split(',') {
+                    let mut pair = param.splitn(2, '=');
+                    let (key, value) = (pair.next()?, pair.next()?.parse().ok()?);
+                    match key {
+                        "m" => memory = Some(value),
+                        "t" => iterations = Some(value),
+                        "p" => parallelism = Some(value),
+                        _ => return None,
+                    }
+                }
+
+                Some(Scheme::Argon2id {
+                    memory: memory?,
+                    iterations: iterations?,
+                    parallelism: parallelism?,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    pub fn hash(&self, password: &str) -> Result<String, Error> {
+        match *self {
+            Scheme::Bcrypt { cost } => Ok(bcrypt::hash(password, cost)?),
+            Scheme::Argon2id {
+                memory,
+                iterations,
+                parallelism,
+            } => {
+                let mut salt = [0; 16];
+                rand_bytes(&mut salt)?;
+                let config = argon2::Config {
+                    variant: Variant::Argon2id,
+                    version: Version::Version13,
+                    mem_cost: memory,
+                    time_cost: iterations,
+                    lanes: parallelism,
+                    thread_mode: ThreadMode::Sequential,
+                    secret: &[],
+                    ad: &[],
+                    hash_length: 32,
+                };
+                Ok(argon2::hash_encoded(password.as_bytes(), &salt, &config)?)
+            }
+        }
+    }
+}
+
+/// Check a password against a stored hash of any supported format
+pub fn verify(password: &str, hash: &str) -> Result<bool, Error> {
+    if hash.starts_with("$2") {
+        Ok(bcrypt::verify(password, hash)?)
+    } else if hash.starts_with("$argon2") {
+        Ok(argon2::verify_encoded(hash, password.as_bytes())?)
+    } else {
+        bail!("unsupported password hash format")
+    }
+}
+
+pub struct Hasher {
+    scheme: Scheme,
+    /// Checked against when there is no user, so an unknown username takes
+    /// as long to reject as a wrong password
+    dummy_hash: String,
+}
+
+impl Hasher {
+    pub fn new(scheme: Scheme, dummy_hash: String) -> Self {
+        Hasher { scheme, dummy_hash }
+    }
+
+    /// Start the pool, sized and configured from the environment
+    ///
+    /// Fails if the configured scheme cannot hash, like a bcrypt cost out of
+    /// range.
+    pub fn start_from_env() -> Result<Addr<Hasher>, Error> {
+        let threads = config::var_or("HASHER_THREADS", 2);
+        let scheme = Scheme::from_env();
+        let dummy_hash = scheme
+            .hash("dummy password")
+            .map_err(|e| format_err!("unable to hash with the configured password scheme: {}", e))?;
+        Ok(SyncArbiter::start(threads, move || Hasher::new(scheme, dummy_hash.clone())))
+    }
+}
+
+impl Actor for Hasher {
+    type Context = SyncContext<Self>;
+}
+
+pub struct HashPassword {
+    pub password: String,
+}
+
+impl Message for HashPassword {
+    type Result = Result<String, Error>;
+}
+
+impl Handler<HashPassword> for Hasher {
+    type Result = Result<String, Error>;
+
+    fn handle(&mut self, msg: HashPassword, _: &mut Self::Context) -> Self::Result {
+        self.scheme.hash(&msg.password)
+    }
+}
+
+/// Check a password, rehashing it if the stored hash is outdated
+pub struct VerifyPassword {
+    pub password: String,
+    /// `None` for a user that does not exist, the password is then checked
+    /// against a dummy hash and rejected
+    pub hash: Option<String>,
+}
+
+pub enum Verified {
+    Incorrect,
+    Correct,
+    /// The password is correct and should be stored with this new hash
+    Rehashed(String),
+}
+
+impl Message for VerifyPassword {
+    type Result = Result<Verified, Error>;
+}
+
+impl Handler<VerifyPassword> for Hasher {
+    type Result = Result<Verified, Error>;
+
+    fn handle(&mut self, msg: VerifyPassword, _: &mut Self::Context) -> Self::Result {
+        let hash = match msg.hash {
+            Some(hash) => hash,
+            None => {
+                verify(&msg.password, &self.dummy_hash)?;
+                return Ok(Verified::Incorrect);
+            }
+        };
+
+        if !verify(&msg.password, &hash)? {
+            return Ok(Verified::Incorrect);
+        }
+
+        if Scheme::of(&hash) == Some(self.scheme) {
+            Ok(Verified::Correct)
+        } else {
+            Ok(Verified::Rehashed(self.scheme.hash(&msg.password)?))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ARGON2: Scheme = Scheme::Argon2id {
+        memory: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    #[test]
+    fn schemes_are_read_from_hashes() {
+        assert_eq!(
+            Scheme::of("$2y$12$L6Bc/AlTQHyd9liGgGEZyOFLPHNgyxeEPfgYfBCVxJ7JIlwxyVU3u"),
+            Some(Scheme::Bcrypt { cost: 12 })
+        );
+        assert_eq!(
+            Scheme::of("$argon2id$v=19$m=19456,t=2,p=1$c2FsdHNhbHQ$aGFzaA"),
+            Some(Scheme::Argon2id {
+                memory: 19_456,
+                iterations: 2,
+                parallelism: 1,
+            })
+        );
+        assert_eq!(Scheme::of("$argon2i$v=19$m=4096,t=3,p=1$c2FsdA$aGFzaA"), None);
+        assert_eq!(Scheme::of("plaintext"), None);
+    }
+
+    #[test]
+    fn hashes_round_trip() {
+        for scheme in &[Scheme::Bcrypt { cost: 4 }, ARGON2] {
+            let hash = scheme.hash("hunter2").unwrap();
+            assert_eq!(Scheme::of(&hash), Some(*scheme));
+            assert!(verify("hunter2", &hash).unwrap());
+            assert!(!verify("hunter3", &hash).unwrap());
+        }
+    }
+}
@@ -14,6 +14,11 @@ extern crate lazy_static;
 #[macro_use]
 extern crate failure;
 
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+extern crate regex;
+
 extern crate actix;
 
 extern crate actix_web;
@@ -32,24 +37,40 @@ extern crate time;
 
 extern crate futures;
 
+extern crate argon2;
 extern crate bcrypt;
 extern crate bytes;
+extern crate unicode_normalization;
 
+pub mod auth;
 pub mod chatserver;
+pub mod config;
 pub mod database;
+pub mod hasher;
+pub mod logging;
+pub mod pagination;
+pub mod request;
 pub mod server;
+pub mod session;
+pub mod sweeper;
+pub mod throttle;
 pub mod token;
+pub mod validation;
 pub mod websocket;
 
 pub use wakkave::protocol_capnp;
 
+use self::auth::AuthService;
 use self::chatserver::ChatServer;
 use self::database::executor::DbExecutor;
 use actix::prelude::*;
+use failure::Error;
+use protocol_capnp::ErrorCode;
 
 pub struct State {
     pub db: Addr<DbExecutor>,
     pub chat: Addr<ChatServer>,
+    pub auth: AuthService,
 }
 
 #[derive(Debug, Fail)]
@@ -60,6 +81,9 @@ pub enum ServerError {
     #[fail(display = "Invalid Token")]
     VerifyToken,
 
+    #[fail(display = "Session is no longer valid")]
+    InvalidSession,
+
     #[fail(display = "unable to insert token in the database")]
     InsertToken,
 
@@ -72,12 +96,24 @@ pub enum ServerError {
     #[fail(display = "unable to remove token from the database")]
     RemoveToken,
 
+    #[fail(display = "unable to find sessions in the database")]
+    FindSessions,
+
     #[fail(display = "unable to add user to chat")]
     JoinChat,
 
     #[fail(display = "unable to add a new user to the database")]
     CreateUser,
 
+    #[fail(display = "Username is already taken")]
+    UsernameTaken,
+
+    #[fail(display = "Username {}", reason)]
+    UsernameInvalid { reason: String },
+
+    #[fail(display = "Password {}", reason)]
+    PasswordTooWeak { reason: String },
+
     #[fail(display = "unable to find user in the database")]
     FindUser,
 
@@ -87,6 +123,117 @@ pub enum ServerError {
     #[fail(display = "Password is incorrect")]
     IncorrectPassword,
 
+    #[fail(display = "User does not exist")]
+    UnknownUser,
+
+    #[fail(display = "Username or password is incorrect")]
+    InvalidCredentials,
+
+    #[fail(
+        display = "Too many failed logins, try again in {} seconds",
+        retry_after
+    )]
+    LoginLocked { retry_after: u64 },
+
     #[fail(display = "Invalid Vote")]
     InvalidVote,
+
+    #[fail(display = "Malformed request: {}", reason)]
+    MalformedRequest { reason: String },
+
+    #[fail(display = "Request is larger than {} bytes", limit)]
+    RequestTooLarge { limit: usize },
+
+    #[fail(display = "Room name {}", reason)]
+    RoomNameInvalid { reason: String },
+
+    #[fail(display = "Invalid cursor")]
+    InvalidCursor,
+
+    #[fail(display = "Voting on this post is closed")]
+    PostClosed,
+
+    #[fail(display = "You cannot vote on your own post")]
+    SelfVote,
+
+    #[fail(display = "Post does not exist")]
+    PostNotFound,
+
+    #[fail(display = "Room does not exist")]
+    RoomNotFound,
+}
+
+impl ServerError {
+    /// The code clients can tell this error apart by
+    pub fn code(&self) -> ErrorCode {
+        match *self {
+            ServerError::CreateToken => ErrorCode::CreateToken,
+            ServerError::VerifyToken => ErrorCode::VerifyToken,
+            ServerError::InvalidSession => ErrorCode::InvalidSession,
+            ServerError::InsertToken => ErrorCode::InsertToken,
+            ServerError::InsertPost => ErrorCode::InsertPost,
+            ServerError::UpdateToken => ErrorCode::UpdateToken,
+            ServerError::RemoveToken => ErrorCode::RemoveToken,
+            ServerError::FindSessions => ErrorCode::FindSessions,
+            ServerError::JoinChat => ErrorCode::JoinChat,
+            ServerError::CreateUser => ErrorCode::CreateUser,
+            ServerError::UsernameTaken => ErrorCode::UsernameTaken,
+            ServerError::UsernameInvalid { .. } => ErrorCode::UsernameInvalid,
+            ServerError::PasswordTooWeak { .. } => ErrorCode::PasswordTooWeak,
+            ServerError::FindUser => ErrorCode::FindUser,
+            ServerError::InsertVote => ErrorCode::InsertVote,
+            ServerError::IncorrectPassword => ErrorCode::IncorrectPassword,
+            // Never tell clients a username is unknown
+            ServerError::UnknownUser | ServerError::InvalidCredentials => {
+                ErrorCode::InvalidCredentials
+            }
+            ServerError::LoginLocked { .. } => ErrorCode::LoginLocked,
+            ServerError::InvalidVote => ErrorCode::InvalidVote,
+            ServerError::MalformedRequest { .. } => ErrorCode::MalformedRequest,
+            ServerError::RequestTooLarge { .. } => ErrorCode::RequestTooLarge,
+            ServerError::RoomNameInvalid { .. } => ErrorCode::RoomNameInvalid,
+            ServerError::InvalidCursor => ErrorCode::InvalidCursor,
+            ServerError::PostClosed => ErrorCode::PostClosed,
+            ServerError::SelfVote => ErrorCode::SelfVote,
+            ServerError::PostNotFound => ErrorCode::PostNotFound,
+            ServerError::RoomNotFound => ErrorCode::RoomNotFound,
+        }
+    }
+
+    /// The request field that caused this error, if it was a single one
+    pub fn field(&self) -> Option<&'static str> {
+        match *self {
+            ServerError::VerifyToken | ServerError::InvalidSession => Some("token"),
+            ServerError::UsernameTaken | ServerError::UsernameInvalid { .. } => Some("username"),
+            ServerError::PasswordTooWeak { .. } | ServerError::IncorrectPassword => {
+                Some("password")
+            }
+            ServerError::InvalidVote => Some("vote"),
+            ServerError::RoomNameInvalid { .. } => Some("name"),
+            ServerError::InvalidCursor => Some("cursor"),
+            ServerError::PostNotFound => Some("postId"),
+            ServerError::RoomNotFound => Some("roomId"),
+            _ => None,
+        }
+    }
+}
+
+/// Fill in the error branch of a response
+///
+/// Errors that did not originate from a `ServerError` are reported as
+/// `malformedRequest` when the request could not be decoded and as `unknown`
+/// otherwise.
+pub fn write_error(mut builder: protocol_capnp::error::Builder, e: &Error) {
+    if let Some(server_error) = e.downcast_ref::<ServerError>() {
+        builder.set_code(server_error.code());
+        if let Some(field) = server_error.field() {
+            builder.set_field(field);
+        }
+    } else if e.downcast_ref::<capnp::Error>().is_some() {
+        builder.set_code(ErrorCode::MalformedRequest);
+    } else {
+        builder.set_code(ErrorCode::Unknown);
+    }
+
+    builder.set_message(&e.to_string());
 }
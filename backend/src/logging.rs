@@ -0,0 +1,75 @@
+//! Logging setup for the backend.
+//!
+//! Records go through the `log` macros and are written by `env_logger`, so
+//! levels can be set per module with `RUST_LOG`, for example
+//! `RUST_LOG=info,backend::websocket=debug`. Every message is redacted before
+//! it is written: JWTs, refresh tokens and anything that looks like a
+//! `password=...` pair are masked, so a careless log call cannot leak them.
+
+use env_logger::{Builder, Env, DEFAULT_FILTER_ENV};
+use regex::Regex;
+use std::io::Write;
+
+const REDACTED: &str = "[redacted]";
+
+lazy_static! {
+    /// Three base64url segments, the first being the encoded `{"` of a header
+    static ref JWT: Regex =
+        Regex::new(r"eyJ[A-Za-z0-9_-]*\.[A-Za-z0-9_-]*\.[A-Za-z0-9_-]*").unwrap();
+    /// Refresh tokens are 32 random bytes encoded as hex
+    static ref REFRESH_TOKEN: Regex = Regex::new(r"\b[0-9a-fA-F]{64}\b").unwrap();
+    static ref SECRET_PAIR: Regex = Regex::new(
+        r"(?i)\b(password|passwd|secret|token|refresh_token|authorization)(\s*[=:]\s*)\S+"
+    ).unwrap();
+}
+
+/// Install the logger, defaulting to the `info` level
+pub fn init() {
+    Builder::from_env(Env::default().filter_or(DEFAULT_FILTER_ENV, "info"))
+        .format(|buf, record| {
+            writeln!(
+                buf,
+                "{} {:<5} {}: {}",
+                buf.timestamp(),
+                record.level(),
+                record.target(),
+                redact(&record.args().to_string())
+            )
+        }).init();
+}
+
+/// Mask credentials and tokens in a log message
+pub fn redact(message: &str) -> String {
+    let message = JWT.replace_all(message, REDACTED);
+    let message = REFRESH_TOKEN.replace_all(&message, REDACTED);
+    SECRET_PAIR
+        .replace_all(&message, "${1}${2}[redacted]")
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_redacted() {
+        let jwt = "eyJ0eXAiOiJKV1QiLCJhbGciOiJIUzI1NiJ9.eyJzdWIiOjF9.c2lnbmF0dXJl";
+        let refresh = "ab".repeat(32);
+        let message = format!("token {} and refresh {}", jwt, refresh);
+
+        let redacted = redact(&message);
+        assert!(!redacted.contains(jwt));
+        assert!(!redacted.contains(&refresh));
+        assert_eq!(redacted, "token [redacted] and refresh [redacted]");
+    }
+
+    #[test]
+    fn secret_pairs_are_redacted() {
+        assert_eq!(
+            redact("username=steve password=hunter2"),
+            "username=steve password=[redacted]"
+        );
+        assert_eq!(redact("Password: hunter2"), "Password: [redacted]");
+        assert_eq!(redact("Purged 3 expired sessions"), "Purged 3 expired sessions");
+    }
+}
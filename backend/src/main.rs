@@ -6,6 +6,7 @@ use dotenv::dotenv;
 
 fn main() {
     dotenv().ok();
+    backend::logging::init();
 
     let server = Server::new();
 
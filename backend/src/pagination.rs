@@ -0,0 +1,133 @@
+//! Cursors clients page through the posts of a room with.
+//!
+//! Posts are ordered by when they were made, their id breaking ties between
+//! posts made in the same microsecond. A cursor marks the last post of a page
+//! and the next page starts right after it, so posts made while a client is
+//! paging neither shift the pages nor show up twice.
+//!
+//! Clients get cursors as opaque text, the microseconds since the unix epoch
+//! and the id of the post separated by a dot.
+
+use super::{config, ServerError};
+use database::models::Post;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Order {
+    NewestFirst,
+    OldestFirst,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cursor {
+    pub created_at: SystemTime,
+    pub id: i32,
+}
+
+impl Cursor {
+    /// The cursor of the page ending with `post`
+    pub fn after(post: &Post) -> Self {
+        Cursor {
+            created_at: post.created_at,
+            id: post.id,
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        let since_epoch = self
+            .created_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_else(|_| Duration::from_secs(0));
+        let micros = since_epoch.as_secs() * 1_000_000 + u64::from(since_epoch.subsec_micros());
+        format!("{}.{}", micros, self.id)
+    }
+
+    pub fn decode(cursor: &str) -> Result<Self, ServerError> {
+        let mut parts = cursor.splitn(2, '.');
+        let micros = parts
+            .next()
+            .and_then(|micros| micros.parse::<u64>().ok())
+            .ok_or(ServerError::InvalidCursor)?;
+        let id = parts
+            .next()
+            .and_then(|id| id.parse::<i32>().ok())
+            .ok_or(ServerError::InvalidCursor)?;
+        let since_epoch = Duration::new(micros / 1_000_000, (micros % 1_000_000) as u32 * 1000);
+        let created_at = UNIX_EPOCH
+            .checked_add(since_epoch)
+            .ok_or(ServerError::InvalidCursor)?;
+
+        Ok(Cursor { created_at, id })
+    }
+}
+
+/// How many posts a page holds
+#[derive(Clone, Copy, Debug)]
+pub struct PageSize {
+    /// Used when a client does not ask for a limit
+    pub default: u16,
+    /// The most posts sent at once, whatever a client asks for
+    pub max: u16,
+}
+
+impl Default for PageSize {
+    fn default() -> Self {
+        PageSize {
+            default: 50,
+            max: 200,
+        }
+    }
+}
+
+impl PageSize {
+    pub fn from_env() -> Self {
+        let default = PageSize::default();
+        PageSize {
+            default: config::var_or("POSTS_PAGE_SIZE", default.default),
+            max: config::var_or("POSTS_PAGE_MAX", default.max),
+        }
+    }
+
+    /// The size of the page sent for a requested `limit`, 0 asking for the
+    /// default
+    pub fn limit(&self, limit: u16) -> u16 {
+        let limit = if limit == 0 { self.default } else { limit };
+        limit.min(self.max).max(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursors_round_trip() {
+        let cursor = Cursor {
+            created_at: UNIX_EPOCH + Duration::new(1_539_820_800, 123_456_000),
+            id: 42,
+        };
+        assert_eq!("1539820800123456.42", cursor.encode());
+        assert_eq!(cursor, Cursor::decode(&cursor.encode()).unwrap());
+    }
+
+    #[test]
+    fn invalid_cursors_are_rejected() {
+        for cursor in &["", "12", "12.", ".12", "a.b", "12.34.56", "-1.2"] {
+            match Cursor::decode(cursor) {
+                Err(ServerError::InvalidCursor) => (),
+                _ => panic!("{:?} should be rejected", cursor),
+            }
+        }
+    }
+
+    #[test]
+    fn page_sizes_are_capped() {
+        let size = PageSize {
+            default: 50,
+            max: 200,
+        };
+        assert_eq!(50, size.limit(0));
+        assert_eq!(10, size.limit(10));
+        assert_eq!(200, size.limit(1000));
+    }
+}
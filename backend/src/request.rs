@@ -0,0 +1,358 @@
+//! Requests decoded out of their Cap'n Proto message.
+//!
+//! The readers generated by capnp borrow the message they were read from, so
+//! a request is copied into a `Request` before any asynchronous work starts.
+//!
+//! Messages come straight from clients and are decoded with explicit limits:
+//! anything larger than `MAX_REQUEST_SIZE`, nested deeper than the schema
+//! needs or otherwise malformed is rejected with a `ServerError` instead of
+//! being read.
+
+use super::{write_error, ServerError};
+use capnp::{
+    message::{Builder, HeapAllocator, ReaderOptions},
+    serialize_packed,
+};
+use failure::Error;
+use pagination::Order;
+use protocol_capnp::{request, response, PostOrder, Vote};
+
+pub enum Request {
+    LoginCredentials {
+        username: String,
+        password: String,
+    },
+    LoginToken(String),
+    Registration {
+        username: String,
+        password: String,
+    },
+    Logout(String),
+    LogoutAll(String),
+    ListSessions(String),
+    RevokeSession {
+        token: String,
+        session_id: String,
+    },
+    ChangePassword {
+        token: String,
+        current_password: String,
+        new_password: String,
+    },
+    DeleteAccount {
+        token: String,
+        password: String,
+    },
+    FetchPosts {
+        token: String,
+        room_id: i32,
+        /// Decoded by the handler, so a bad one is reported as such
+        cursor: String,
+        limit: u16,
+        order: Order,
+    },
+    CreatePost {
+        token: String,
+        content: String,
+        room_id: i32,
+    },
+    UserVote {
+        token: String,
+        vote: Vote,
+        post_id: i32,
+    },
+    ConnectToChat(String),
+    RefreshSession(String),
+    JoinRoom {
+        token: String,
+        name: String,
+    },
+    LeaveRoom {
+        token: String,
+        room_id: i32,
+    },
+    ListRooms(String),
+}
+
+/// Largest request accepted in bytes, packed
+pub const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+/// Options requests are read with
+///
+/// The traversal limit bounds the words read for a request, which unpacked
+/// can be several times its packed size. No request nests deeper than a
+/// group in a union, so a small nesting limit is plenty.
+pub fn reader_options() -> ReaderOptions {
+    let mut options = ReaderOptions::new();
+    options
+        .traversal_limit_in_words(4 * MAX_REQUEST_SIZE as u64 / 8)
+        .nesting_limit(8);
+    options
+}
+
+impl Request {
+    /// Decode a request along with the id its response is tagged with
+    pub fn decode(data: &[u8]) -> Result<(u64, Self), Error> {
+        if data.len() > MAX_REQUEST_SIZE {
+            return Err(ServerError::RequestTooLarge {
+                limit: MAX_REQUEST_SIZE,
+            }.into());
+        }
+
+        Self::read(data).map_err(|e| {
+            ServerError::MalformedRequest {
+                reason: e.to_string(),
+            }.into()
+        })
+    }
+
+    fn read(mut data: &[u8]) -> Result<(u64, Self), Error> {
+        let reader = serialize_packed::read_message(&mut data, reader_options())?;
+        let req = reader.get_root::<request::Reader>()?;
+
+        let request = match req.which()? {
+            request::Login(data) => match data.which()? {
+                request::login::Credentials(data) => Request::LoginCredentials {
+                    username: data.get_username()?.to_string(),
+                    password: data.get_password()?.to_string(),
+                },
+                request::login::Token(token) => Request::LoginToken(token?.to_string()),
+            },
+            request::Registration(data) => Request::Registration {
+                username: data.get_username()?.to_string(),
+                password: data.get_password()?.to_string(),
+            },
+            request::Logout(token) => Request::Logout(token?.to_string()),
+            request::LogoutAll(token) => Request::LogoutAll(token?.to_string()),
+            request::ListSessions(token) => Request::ListSessions(token?.to_string()),
+            request::RevokeSession(data) => Request::RevokeSession {
+                token: data.get_token()?.to_string(),
+                session_id: data.get_session_id()?.to_string(),
+            },
+            request::ChangePassword(data) => Request::ChangePassword {
+                token: data.get_token()?.to_string(),
+                current_password: data.get_current_password()?.to_string(),
+                new_password: data.get_new_password()?.to_string(),
+            },
+            request::DeleteAccount(data) => Request::DeleteAccount {
+                token: data.get_token()?.to_string(),
+                password: data.get_password()?.to_string(),
+            },
+            request::FetchPosts(token) => Request::FetchPosts {
+                token: token?.to_string(),
+                room_id: 0,
+                cursor: String::new(),
+                limit: 0,
+                order: Order::NewestFirst,
+            },
+            request::FetchPostsPage(data) => Request::FetchPosts {
+                token: data.get_token()?.to_string(),
+                room_id: data.get_room_id(),
+                cursor: data.get_cursor()?.to_string(),
+                limit: data.get_limit(),
+                order: match data.get_order()? {
+                    PostOrder::NewestFirst => Order::NewestFirst,
+                    PostOrder::OldestFirst => Order::OldestFirst,
+                },
+            },
+            request::CreatePost(data) => Request::CreatePost {
+                token: data.get_token()?.to_string(),
+                content: data.get_content()?.to_string(),
+                room_id: data.get_room_id(),
+            },
+            request::UserVote(data) => Request::UserVote {
+                token: data.get_token()?.to_string(),
+                vote: data.get_vote()?,
+                post_id: data.get_post_id(),
+            },
+            request::ConnectToChat(token) => Request::ConnectToChat(token?.to_string()),
+            request::RefreshSession(token) => Request::RefreshSession(token?.to_string()),
+            request::JoinRoom(data) => Request::JoinRoom {
+                token: data.get_token()?.to_string(),
+                name: data.get_name()?.to_string(),
+            },
+            request::LeaveRoom(data) => Request::LeaveRoom {
+                token: data.get_token()?.to_string(),
+                room_id: data.get_room_id(),
+            },
+            request::ListRooms(token) => Request::ListRooms(token?.to_string()),
+        };
+
+        Ok((req.get_request_id(), request))
+    }
+
+    /// The branch of `Response` this request is answered on
+    pub fn reply(&self) -> Reply {
+        match *self {
+            Request::LoginCredentials { .. }
+            | Request::LoginToken(_)
+            | Request::Registration { .. } => Reply::Login,
+            Request::Logout(_) | Request::LogoutAll(_) => Reply::Logout,
+            Request::ListSessions(_) => Reply::ListSessions,
+            Request::RevokeSession { .. } => Reply::RevokeSession,
+            Request::ChangePassword { .. } => Reply::ChangePassword,
+            Request::DeleteAccount { .. } => Reply::DeleteAccount,
+            Request::FetchPosts { .. } => Reply::FetchPosts,
+            Request::CreatePost { .. } => Reply::CreatePost,
+            Request::UserVote { .. } => Reply::UserVote,
+            Request::ConnectToChat(_) => Reply::ConnectToChat,
+            Request::RefreshSession(_) => Reply::RefreshSession,
+            Request::JoinRoom { .. } => Reply::JoinRoom,
+            Request::LeaveRoom { .. } => Reply::LeaveRoom,
+            Request::ListRooms(_) => Reply::ListRooms,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reply {
+    /// For requests that could not be decoded
+    Invalid,
+    Login,
+    Logout,
+    ListSessions,
+    RevokeSession,
+    ChangePassword,
+    DeleteAccount,
+    FetchPosts,
+    CreatePost,
+    UserVote,
+    ConnectToChat,
+    RefreshSession,
+    JoinRoom,
+    LeaveRoom,
+    ListRooms,
+}
+
+impl Reply {
+    /// Build the error response for a request that failed
+    pub fn error(self, e: &Error) -> Builder<HeapAllocator> {
+        let mut builder = Builder::new_default();
+        {
+            let res = builder.init_root::<response::Builder>();
+            let error = match self {
+                Reply::Invalid => res.init_invalid_request(),
+                Reply::Login => res.init_login().init_error(),
+                Reply::Logout => res.init_logout().init_error(),
+                Reply::ListSessions => res.init_list_sessions().init_error(),
+                Reply::RevokeSession => res.init_revoke_session().init_error(),
+                Reply::ChangePassword => res.init_change_password().init_error(),
+                Reply::DeleteAccount => res.init_delete_account().init_error(),
+                Reply::FetchPosts => res.init_fetch_posts().init_error(),
+                Reply::CreatePost => res.init_create_post().init_error(),
+                Reply::UserVote => res.init_user_vote().init_error(),
+                Reply::ConnectToChat => res.init_connect_to_chat().init_error(),
+                Reply::RefreshSession => res.init_refresh_session().init_error(),
+                Reply::JoinRoom => res.init_join_room().init_error(),
+                Reply::LeaveRoom => res.init_leave_room().init_error(),
+                Reply::ListRooms => res.init_list_rooms().init_error(),
+            };
+            write_error(error, e);
+        }
+        builder
+    }
+}
+
+/// Tag a response with the id of its request and serialize it
+pub fn write_response(
+    builder: &mut Builder<HeapAllocator>,
+    request_id: u64,
+) -> Result<Vec<u8>, Error> {
+    builder
+        .get_root::<response::Builder>()?
+        .set_request_id(request_id);
+
+    let mut data = Vec::new();
+    serialize_packed::write_message(&mut data, builder)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode<F>(build: F) -> Vec<u8>
+    where
+        F: FnOnce(request::Builder),
+    {
+        let mut builder = Builder::new_default();
+        build(builder.init_root::<request::Builder>());
+        let mut data = Vec::new();
+        serialize_packed::write_message(&mut data, &builder).unwrap();
+        data
+    }
+
+    fn decode_error(data: &[u8]) -> ServerError {
+        match Request::decode(data) {
+            Ok(_) => panic!("request should not decode"),
+            Err(e) => e.downcast::<ServerError>().unwrap(),
+        }
+    }
+
+    #[test]
+    fn requests_decode() {
+        let data = encode(|mut req| {
+            req.set_request_id(7);
+            let mut fetch = req.init_fetch_posts_page();
+            fetch.set_token("token");
+            fetch.set_order(PostOrder::OldestFirst);
+        });
+
+        match Request::decode(&data).unwrap() {
+            (
+                7,
+                Request::FetchPosts {
+                    ref token,
+                    room_id: 0,
+                    ref cursor,
+                    limit: 0,
+                    order: Order::OldestFirst,
+                },
+            ) if token == "token" && cursor.is_empty() => (),
+            _ => panic!("decoded the wrong request"),
+        }
+    }
+
+    #[test]
+    fn legacy_fetch_posts_decodes() {
+        let data = encode(|mut req| req.set_fetch_posts("token"));
+
+        match Request::decode(&data).unwrap() {
+            (
+                0,
+                Request::FetchPosts {
+                    ref token,
+                    room_id: 0,
+                    limit: 0,
+                    ..
+                },
+            ) if token == "token" => (),
+            _ => panic!("decoded the wrong request"),
+        }
+    }
+
+    #[test]
+    fn garbage_is_rejected() {
+        for data in &[&b""[..], &b"\xff\xff\xff\xff\xff\xff\xff\xff"[..], &[0; 3][..]] {
+            match decode_error(data) {
+                ServerError::MalformedRequest { .. } => (),
+                e => panic!("unexpected error: {}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn oversized_requests_are_rejected() {
+        let content = "a".repeat(MAX_REQUEST_SIZE);
+        let data = encode(|req| {
+            let mut post = req.init_create_post();
+            post.set_token("token");
+            post.set_content(&content);
+        });
+
+        match decode_error(&data) {
+            ServerError::RequestTooLarge { .. } => (),
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+}
@@ -1,28 +1,29 @@
 use super::{
+    auth::{write_login_success, write_refresh_success, AuthFuture, AuthService},
     chatserver::ChatServer,
-    database::executor::{
-        CreatePost, CreateSession, CreateUser, DbExecutor, DeleteSession, FetchPosts, FindUser,
-        FindUserID, UpdateSession, UserVote,
-    },
-    token::Token,
+    config,
+    database::{executor::DbExecutor, username_keys},
+    hasher::Hasher,
+    request::{write_response, Reply, Request, MAX_REQUEST_SIZE},
+    session::{ClientInfo, SessionCache},
+    sweeper::SessionSweeper,
+    throttle::LoginThrottle,
+    validation::{self, Policy},
     websocket::Ws,
-    State,
+    ServerError, State,
 };
 use actix::{prelude::*, SystemRunner};
 use actix_web::{
-    error, fs::StaticFiles, http, server, ws, App, AsyncResponder, Error, FutureResponse,
-    HttpMessage, HttpRequest, HttpResponse, Responder,
+    error::{self, PayloadError},
+    fs::StaticFiles,
+    http, server, ws, App, AsyncResponder, Error, FutureResponse, HttpMessage, HttpRequest,
+    HttpResponse, Responder,
 };
 use bytes::Bytes;
-use capnp::{
-    self,
-    message::{Builder, HeapAllocator, ReaderOptions},
-    serialize_packed, text,
-};
+use capnp::message::{Builder, HeapAllocator};
 use diesel::{prelude::*, r2d2::ConnectionManager};
 use failure::Error as FailError;
-use futures::Future;
-use protocol_capnp::{request, response, Vote};
+use futures::{future, Future};
 use r2d2::Pool;
 use std::env;
 
@@ -31,192 +32,135 @@ pub struct Server {
 }
 
 fn handle_request_login_token(
-    data: Result<text::Reader, capnp::Error>,
-    db: Addr<DbExecutor>,
-) -> Result<Vec<u8>, FailError> {
-    let token = data?;
-    println!("Renewing Token: {} \n", token);
-
-    let (new_id, user_id) = Token::verify(token)?;
-
-    let new_token = db
-        .send(UpdateSession {
-            old_id: token.to_string(),
-            new_id,
-        }).wait()??;
-
-    let user = db.send(FindUserID { user_id }).wait()??;
-
-    match user {
-        Some(user) => {
-            let mut builder = Builder::new_default();
-            {
-                let mut success = builder
-                    .init_root::<response::Builder>()
-                    .init_login()
-                    .init_success();
-
-                success.set_token(&new_token.id);
-
-                let mut u = success.init_user();
-                u.set_id(user.id);
-                u.set_username(&user.username);
-                u.set_karma(user.karma);
-                u.set_streak(user.streak);
-            }
+    token: &str,
+    auth: &AuthService,
+) -> AuthFuture<Builder<HeapAllocator>> {
+    debug!("Resuming session with an access token");
 
-            let mut res = Vec::new();
-            serialize_packed::write_message(&mut res, &builder)?;
-            Ok(res)
-        }
-        None => Err(super::ServerError::FindUser.into()),
-    }
+    Box::new(auth.resume(token).map(|authenticated| {
+        let mut builder = Builder::new_default();
+        write_login_success(&mut builder, &authenticated);
+        builder
+    }))
 }
 
 fn handle_request_login_credentials(
-    data: request::login::credentials::Reader,
-    db: Addr<DbExecutor>,
-) -> Result<Vec<u8>, FailError> {
-    let name = data.get_username()?;
-    let password = data.get_password()?;
-    println!("Name: {} \nPassword: {}", name, password);
-
-    let user = db
-        .send(FindUser {
-            username: name.to_string(),
-            password: password.to_string(),
-        }).wait()??;
-
-    match user {
-        Some(user) => {
-            let token = db
-                .send(CreateSession {
-                    id: Token::create(user.id)?,
-                }).wait()??;
-            let mut builder = Builder::new_default();
-            {
-                let mut success = builder
-                    .init_root::<response::Builder>()
-                    .init_login()
-                    .init_success();
-
-                success.set_token(&token.id);
-
-                let mut u = success.init_user();
-                u.set_id(user.id);
-                u.set_username(&user.username);
-                u.set_karma(user.karma);
-                u.set_streak(user.streak);
-            }
-
-            let mut res = Vec::new();
-            serialize_packed::write_message(&mut res, &builder)?;
-            Ok(res)
-        }
-        None => Err(super::ServerError::FindUser.into()),
-    }
+    name: String,
+    password: String,
+    auth: &AuthService,
+    client: ClientInfo,
+) -> AuthFuture<Builder<HeapAllocator>> {
+    debug!("Login attempt for {}", validation::username_digest(&name));
+
+    Box::new(auth.login(name, password, client).map(|authenticated| {
+        let mut builder = Builder::new_default();
+        write_login_success(&mut builder, &authenticated);
+        builder
+    }))
 }
 
 fn handle_request_registration(
-    data: request::registration::Reader,
-    db: Addr<DbExecutor>,
-) -> Result<Vec<u8>, FailError> {
-    let username = data.get_username()?.to_string();
-    let password = data.get_password()?.to_string();
-    let user = db.send(CreateUser { username, password }).wait()??;
-    let res = {
+    username: String,
+    password: String,
+    auth: &AuthService,
+    client: ClientInfo,
+) -> AuthFuture<Builder<HeapAllocator>> {
+    Box::new(auth.register(username, password, client).map(|authenticated| {
         let mut builder = Builder::new_default();
-        {
-            let mut success = builder
-                .init_root::<response::Builder>()
-                .init_login()
-                .init_success();
-            success.set_token(&Token::create(user.id)?);
-
-            let mut u = success.init_user();
-            u.set_id(user.id);
-            u.set_username(&user.username);
-            u.set_karma(user.karma);
-            u.set_streak(user.streak);
-        }
-        let mut res = Vec::new();
-        serialize_packed::write_message(&mut res, &builder)?;
-        res
-    };
+        write_login_success(&mut builder, &authenticated);
+        builder
+    }))
+}
 
-    Ok(res)
+fn handle_request_refresh_session(
+    refresh_token: String,
+    auth: &AuthService,
+    client: ClientInfo,
+) -> AuthFuture<Builder<HeapAllocator>> {
+    Box::new(auth.refresh(refresh_token, client).map(|authenticated| {
+        let mut builder = Builder::new_default();
+        write_refresh_success(&mut builder, &authenticated);
+        builder
+    }))
 }
 
-fn login_register(req: &HttpRequest<State>) -> FutureResponse<Bytes> {
-    let db = req.state().db.clone();
+/// Handle a request made to `/login`, the only one made before a websocket
+/// is opened
+///
+/// Responses carry the id of their request like those sent over the
+/// websocket do.
+pub fn login_register(req: &HttpRequest<State>) -> FutureResponse<Bytes> {
+    let auth = req.state().auth.clone();
+    let client = ClientInfo::from_request(req);
     req.body() // <- get Body future
-        .from_err()
-        .and_then(|bytes: Bytes| {
-            let reader = serialize_packed::read_message(&mut bytes.as_ref(), ReaderOptions::new())
-                .expect("Error reading message");
-
-            let request = reader
-                .get_root::<request::Reader>()
-                .expect("Error getting message root");
-
-            match request.which() {
-                Ok(request::Login(data)) => match data.which() {
-                    Ok(request::login::Credentials(data)) => {
-                        match handle_request_login_credentials(data, db) {
-                            Ok(res) => Ok(Bytes::from(res)), //self.connect_to_chat(ctx),
-                            Err(e) => {
-                                let mut builder = Builder::new_default();
-                                builder
-                                    .init_root::<response::Builder>()
-                                    .init_login()
-                                    .set_error(&e.to_string());
-                                let mut error_res = Vec::new();
-                                serialize_packed::write_message(&mut error_res, &builder)
-                                    .map_err(|e| error::ErrorInternalServerError(e.to_string()))?;
-                                println!("{}", &e.to_string());
-                                Ok(Bytes::from(error_res))
-                            }
-                        }
+        .limit(MAX_REQUEST_SIZE)
+        .then(move |body| -> Box<Future<Item = Bytes, Error = Error>> {
+            let bytes = match body {
+                Ok(bytes) => bytes,
+                // Answered like a request too large to decode
+                Err(PayloadError::Overflow) => {
+                    let e = ServerError::RequestTooLarge {
+                        limit: MAX_REQUEST_SIZE,
+                    };
+                    warn!("Rejected a login request: {}", e);
+                    return Box::new(future::result(write_reply_error(
+                        Reply::Invalid,
+                        &e.into(),
+                        0,
+                    )));
+                }
+                Err(e) => return Box::new(future::err(e.into())),
+            };
+            let (request_id, request) = match Request::decode(&bytes) {
+                Ok(decoded) => decoded,
+                Err(e) => {
+                    warn!("Rejected a login request: {}", e);
+                    // The id is unknown when the request could not be read
+                    return Box::new(future::result(write_reply_error(Reply::Invalid, &e, 0)));
+                }
+            };
+
+            let reply = request.reply();
+            let res = match request {
+                Request::LoginCredentials { username, password } => {
+                    handle_request_login_credentials(username, password, &auth, client)
+                }
+                Request::LoginToken(token) => handle_request_login_token(&token, &auth),
+                Request::Registration { username, password } => {
+                    handle_request_registration(username, password, &auth, client)
+                }
+                Request::RefreshSession(refresh_token) => {
+                    handle_request_refresh_session(refresh_token, &auth, client)
+                }
+                _ => return Box::new(future::err(error::ErrorBadRequest("Invalid data"))),
+            };
+
+            Box::new(res.then(move |res| match res {
+                Ok(mut builder) => write_reply(&mut builder, request_id),
+                Err(e) => {
+                    if reply == Reply::Login {
+                        warn!("Login failed: {}", e);
                     }
-                    Ok(request::login::Token(data)) => match handle_request_login_token(data, db) {
-                        Ok(res) => Ok(Bytes::from(res)),
-                        Err(e) => {
-                            let mut builder = Builder::new_default();
-                            builder
-                                .init_root::<response::Builder>()
-                                .init_login()
-                                .set_error(&e.to_string());
-                            let mut error_res = Vec::new();
-                            serialize_packed::write_message(&mut error_res, &builder)?;
+                    write_reply_error(reply, &e, request_id)
+                }
+            }))
+        }).responder()
+}
 
-                            Ok(Bytes::from(error_res))
-                        }
-                    },
-                    Err(::capnp::NotInSchema(_)) => {
-                        Err(error::ErrorInternalServerError("Invalid data"))
-                    }
-                },
-                Ok(request::Registration(data)) => match handle_request_registration(data, db) {
-                    Ok(res) => Ok(Bytes::from(res)),
-                    Err(e) => {
-                        let mut builder = Builder::new_default();
-                        builder
-                            .init_root::<response::Builder>()
-                            .init_login()
-                            .set_error(&e.to_string());
-                        let mut error_res = Vec::new();
-                        serialize_packed::write_message(&mut error_res, &builder)?;
+/// Serialize the response to a request made to `/login`
+fn write_reply(builder: &mut Builder<HeapAllocator>, request_id: u64) -> Result<Bytes, Error> {
+    write_response(builder, request_id)
+        .map(Bytes::from)
+        .map_err(|e| error::ErrorInternalServerError(e.to_string()))
+}
 
-                        Ok(Bytes::from(error_res))
-                    }
-                },
-                _ => Err(error::ErrorInternalServerError("Invalid data")),
-            }
-        }).responder()
+/// Serialize the error response to a request made to `/login`
+fn write_reply_error(reply: Reply, e: &FailError, request_id: u64) -> Result<Bytes, Error> {
+    write_reply(&mut reply.error(e), request_id)
 }
 
 fn connect_ws(req: &HttpRequest<State>) -> Result<HttpResponse, Error> {
-    ws::start(req, Ws::default())
+    ws::start(req, Ws::new(ClientInfo::from_request(req)))
 }
 
 impl Server {
@@ -225,19 +169,35 @@ impl Server {
         let runner = actix::System::new("Wakkave Server");
 
         let database_url = env::var("DATABASE_URL").expect("Expected a database url to be set");
+        // Every executor thread holds on to at most one connection at a time
+        let db_threads = config::var_or("DB_THREADS", 4);
         let manager = ConnectionManager::<PgConnection>::new(database_url);
         let pool = Pool::builder()
+            .max_size(db_threads as u32)
             .build(manager)
             .expect("Failed to create pool");
         embedded_migrations::run(&pool.get().unwrap());
-        let db_addr = SyncArbiter::start(1, move || DbExecutor(pool.clone()));
+        username_keys::backfill(&pool.get().unwrap()).expect("Failed to backfill username keys");
+        let db_addr = SyncArbiter::start(db_threads, move || DbExecutor(pool.clone()));
+        let hasher_addr = Hasher::start_from_env().expect("Invalid password hashing configuration");
         let db_clone = db_addr.clone();
         let chat_addr = Arbiter::start(move |_| ChatServer::new(db_clone));
+        let auth = AuthService::new(
+            db_addr.clone(),
+            hasher_addr,
+            SessionCache::from_env(),
+            Policy::from_env(),
+            LoginThrottle::from_env(),
+        );
+        SessionSweeper::from_env(db_addr.clone())
+            .expect("Invalid session sweeper configuration")
+            .start();
 
         server::new(move || {
             App::with_state(State {
                 db: db_addr.clone(),
                 chat: chat_addr.clone(),
+                auth: auth.clone(),
             }).resource("/ws/", |r| r.f(connect_ws))
             .resource("/login", |r| r.method(http::Method::POST).f(login_register))
             .default_resource(|r| r.h(http::NormalizePath::default()))
@@ -0,0 +1,251 @@
+//! Verification of access tokens against the sessions they were issued for.
+//!
+//! A token is only accepted while its session still exists, so logging out
+//! revokes it immediately. Sessions that were found recently are cached to
+//! avoid a database round trip for every message.
+//!
+//! The IP a client connects from is used to throttle logins and recorded on
+//! its sessions. It is the address of the peer unless that is one of the
+//! `TRUSTED_PROXIES`, only then is the `X-Forwarded-For` header believed.
+
+use super::{
+    config,
+    database::executor::{DbExecutor, FindSession},
+    token::Token,
+    ServerError,
+};
+use actix::prelude::*;
+use actix_web::{http::header, HttpRequest};
+use failure::Error;
+use futures::{future, Future};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Entries are pruned once the cache grows past this size
+const PRUNE_THRESHOLD: usize = 10_000;
+
+lazy_static! {
+    static ref TRUSTED_PROXIES: Vec<IpAddr> =
+        trusted_proxies(&config::var_or("TRUSTED_PROXIES", String::new()));
+}
+
+/// Details about the client a session was started from
+#[derive(Clone, Debug, Default)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn from_request<S>(req: &HttpRequest<S>) -> Self {
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let forwarded_for = req
+            .headers()
+            .get("x-forwarded-for")
+            .and_then(|value| value.to_str().ok());
+        let ip_address =
+            client_ip(req.peer_addr(), forwarded_for, &TRUSTED_PROXIES).map(|ip| ip.to_string());
+
+        ClientInfo {
+            user_agent,
+            ip_address,
+        }
+    }
+}
+
+/// Parse a comma separated list of proxy addresses
+fn trusted_proxies(list: &str) -> Vec<IpAddr> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|proxy| !proxy.is_empty())
+        .filter_map(|proxy| match proxy.parse() {
+            Ok(ip) => Some(ip),
+            Err(_) => {
+                warn!("Ignoring trusted proxy {:?}, it is not an IP address", proxy);
+                None
+            }
+        }).collect()
+}
+
+/// The address of the client behind any trusted proxies
+///
+/// Every proxy appends the address it got the request from to
+/// `X-Forwarded-For`, so the client is the last address in it that is not a
+/// trusted proxy. Anything before that may have been made up by the client.
+fn client_ip(
+    peer: Option<SocketAddr>,
+    forwarded_for: Option<&str>,
+    trusted: &[IpAddr],
+) -> Option<IpAddr> {
+    let mut client = peer?.ip();
+    if let Some(forwarded_for) = forwarded_for {
+        for hop in forwarded_for.rsplit(',') {
+            if !trusted.contains(&client) {
+                break;
+            }
+            match hop.trim().parse() {
+                Ok(ip) => client = ip,
+                Err(_) => break,
+            }
+        }
+    }
+    Some(client)
+}
+
+struct CachedSession {
+    user_id: i32,
+    cached_at: Instant,
+}
+
+/// A cache of sessions known to be valid, shared by every worker
+///
+/// Revocations made on this instance take effect at once. Another backend
+/// instance may keep accepting a revoked session for at most the cache's
+/// time to live, configured with `SESSION_CACHE_TTL` in seconds.
+#[derive(Clone)]
+pub struct SessionCache {
+    ttl: Duration,
+    entries: Arc<Mutex<HashMap<String, CachedSession>>>,
+}
+
+impl SessionCache {
+    pub fn new(ttl: Duration) -> Self {
+        SessionCache {
+            ttl,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(Duration::from_secs(config::var_or("SESSION_CACHE_TTL", 30)))
+    }
+
+    /// Verify an access token and check that its session has not been revoked
+    pub fn verify(
+        &self,
+        db: &Addr<DbExecutor>,
+        token: &str,
+    ) -> Box<Future<Item = Token, Error = Error>> {
+        let token = match Token::verify(token) {
+            Ok(token) => token,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        if self.contains(token.session_id(), token.user_id()) {
+            return Box::new(future::ok(token));
+        }
+
+        let cache = self.clone();
+        Box::new(
+            db.send(FindSession {
+                session_id: token.session_id().to_string(),
+            }).from_err()
+            .and_then(move |res| -> Result<Token, Error> {
+                let session = res?.ok_or(ServerError::InvalidSession)?;
+                if session.user_id != token.user_id() {
+                    return Err(ServerError::InvalidSession.into());
+                }
+
+                cache.insert(&session.id, session.user_id);
+                Ok(token)
+            }),
+        )
+    }
+
+    /// Forget a session that has been deleted
+    pub fn remove(&self, session_id: &str) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.remove(session_id);
+        }
+    }
+
+    /// Forget every session belonging to a user
+    pub fn remove_user(&self, user_id: i32) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.retain(|_, session| session.user_id != user_id);
+        }
+    }
+
+    fn contains(&self, session_id: &str, user_id: i32) -> bool {
+        match self.entries.lock() {
+            Ok(entries) => entries.get(session_id).map_or(false, |session| {
+                session.user_id == user_id && session.cached_at.elapsed() < self.ttl
+            }),
+            Err(_) => false,
+        }
+    }
+
+    fn insert(&self, session_id: &str, user_id: i32) {
+        if let Ok(mut entries) = self.entries.lock() {
+            if entries.len() >= PRUNE_THRESHOLD {
+                let ttl = self.ttl;
+                entries.retain(|_, session| session.cached_at.elapsed() < ttl);
+            }
+
+            entries.insert(
+                session_id.to_string(),
+                CachedSession {
+                    user_id,
+                    cached_at: Instant::now(),
+                },
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use throttle::{Limits, LoginThrottle};
+
+    fn peer(ip: &str) -> Option<SocketAddr> {
+        Some(SocketAddr::new(ip.parse().unwrap(), 41_234))
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_from_untrusted_peers() {
+        let ip = client_ip(peer("203.0.113.7"), Some("10.1.2.3"), &[]);
+        assert_eq!(Some("203.0.113.7".parse().unwrap()), ip);
+    }
+
+    #[test]
+    fn forwarded_for_is_followed_through_trusted_proxies() {
+        let trusted = trusted_proxies("10.0.0.1, 10.0.0.2");
+        // The client made up the first address, the proxies added the others
+        let forwarded_for = Some("1.1.1.1, 203.0.113.7, 10.0.0.2");
+        let ip = client_ip(peer("10.0.0.1"), forwarded_for, &trusted);
+        assert_eq!(Some("203.0.113.7".parse().unwrap()), ip);
+    }
+
+    #[test]
+    fn spoofed_forwarded_for_does_not_reset_the_throttle() {
+        let throttle = LoginThrottle::new(Limits {
+            ip_lockout: 5,
+            ..Limits::default()
+        });
+        // Every attempt claims to be forwarded for another address
+        let ip_of = |spoofed: &str| {
+            client_ip(peer("203.0.113.7"), Some(spoofed), &[]).map(|ip| ip.to_string())
+        };
+
+        for i in 0..5 {
+            let ip = ip_of(&format!("198.51.100.{}", i));
+            let _ = throttle.check(ip.as_ref().map(String::as_str), &format!("user{}", i));
+        }
+
+        let ip = ip_of("198.51.100.99");
+        assert!(
+            throttle
+                .check(ip.as_ref().map(String::as_str), "someone")
+                .is_err()
+        );
+    }
+}
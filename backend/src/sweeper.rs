@@ -0,0 +1,96 @@
+//! SessionSweeper is an actor. It periodically deletes sessions whose
+//! refresh token has expired, since nothing else ever removes them.
+//!
+//! Every batch is deleted by a message of its own, and the next one is only
+//! sent once the previous was done, so a large backlog never ties up a
+//! database executor for longer than a single batch.
+
+use super::{
+    config,
+    database::executor::{DbExecutor, PurgeExpiredSessions},
+};
+use actix::{fut, prelude::*};
+use failure::Error;
+use std::time::Duration;
+
+pub struct SessionSweeper {
+    db: Addr<DbExecutor>,
+    /// Time between two sweeps
+    interval: Duration,
+    /// Maximum number of sessions deleted by a single statement
+    batch_size: i64,
+    /// Sessions deleted by the sweep in progress, `None` between sweeps
+    purged: Option<usize>,
+}
+
+impl SessionSweeper {
+    pub fn new(db: Addr<DbExecutor>, interval: Duration, batch_size: i64) -> Self {
+        SessionSweeper {
+            db,
+            interval,
+            batch_size,
+            purged: None,
+        }
+    }
+
+    /// Configured through `SESSION_SWEEP_INTERVAL` (in seconds) and
+    /// `SESSION_SWEEP_BATCH_SIZE`, which both have to be positive
+    pub fn from_env(db: Addr<DbExecutor>) -> Result<Self, Error> {
+        let interval: i64 = config::var_or("SESSION_SWEEP_INTERVAL", 3600);
+        if interval <= 0 {
+            bail!("SESSION_SWEEP_INTERVAL must be positive, got {}", interval);
+        }
+        let batch_size = config::var_or("SESSION_SWEEP_BATCH_SIZE", 1000);
+        if batch_size <= 0 {
+            bail!("SESSION_SWEEP_BATCH_SIZE must be positive, got {}", batch_size);
+        }
+
+        Ok(Self::new(db, Duration::from_secs(interval as u64), batch_size))
+    }
+
+    fn sweep(&mut self, ctx: &mut Context<Self>) {
+        // A sweep through a large backlog can outlast the interval
+        if self.purged.is_some() {
+            return;
+        }
+
+        self.purged = Some(0);
+        self.purge_batch(ctx);
+    }
+
+    fn purge_batch(&mut self, ctx: &mut Context<Self>) {
+        let task = self
+            .db
+            .send(PurgeExpiredSessions {
+                batch_size: self.batch_size,
+            }).into_actor(self)
+            .then(|res, act, ctx| {
+                let purged = act.purged.unwrap_or(0);
+                match res {
+                    Ok(Ok(batch)) => {
+                        act.purged = Some(purged + batch);
+                        // A full batch means more sessions may have expired
+                        if batch as i64 >= act.batch_size {
+                            act.purge_batch(ctx);
+                            return fut::ok(());
+                        }
+                        info!("Purged {} expired sessions", purged + batch);
+                    }
+                    Ok(Err(e)) => error!("Unable to purge expired sessions: {}", e),
+                    Err(e) => error!("Unable to purge expired sessions: {}", e),
+                }
+                act.purged = None;
+                fut::ok(())
+            });
+        ctx.spawn(task);
+    }
+}
+
+impl Actor for SessionSweeper {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.sweep(ctx);
+        ctx.run_interval(self.interval, |act, ctx| act.sweep(ctx));
+    }
+}
@@ -0,0 +1,387 @@
+//! Throttling of login attempts.
+//!
+//! Failed logins are counted per client IP and per username. After a few free
+//! attempts every further failure doubles how long the next attempt has to
+//! wait, and past a limit the IP or username is locked out for a while. The
+//! check happens before the password is verified, so a throttled client cannot
+//! keep the database threads busy hashing either.
+//!
+//! An attempt let through is counted as failed right away, under the same
+//! lock as the check, and only taken back once it succeeded. Concurrent
+//! attempts therefore cannot all slip past the limit before the first of them
+//! failed.
+
+use super::{config, validation, ServerError};
+use std::{
+    cmp,
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Entries are first pruned once the map grows past this size
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Clone, Debug)]
+pub struct Limits {
+    /// Failures allowed before backing off
+    pub free_attempts: u32,
+    /// Failures after which a username is locked out
+    pub username_lockout: u32,
+    /// Failures after which an IP is locked out, higher since many users can
+    /// share one address
+    pub ip_lockout: u32,
+    pub backoff_base: Duration,
+    pub backoff_max: Duration,
+    pub lockout: Duration,
+    /// Failures are forgotten once the last one is older than this
+    pub window: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            free_attempts: 3,
+            username_lockout: 10,
+            ip_lockout: 100,
+            backoff_base: Duration::from_secs(1),
+            backoff_max: Duration::from_secs(300),
+            lockout: Duration::from_secs(900),
+            window: Duration::from_secs(900),
+        }
+    }
+}
+
+impl Limits {
+    pub fn from_env() -> Self {
+        let default = Limits::default();
+        Limits {
+            free_attempts: config::var_or("LOGIN_FREE_ATTEMPTS", default.free_attempts),
+            username_lockout: config::var_or("LOGIN_LOCKOUT_ATTEMPTS", default.username_lockout),
+            ip_lockout: config::var_or("LOGIN_IP_LOCKOUT_ATTEMPTS", default.ip_lockout),
+            backoff_base: Duration::from_secs(config::var_or(
+                "LOGIN_BACKOFF_BASE",
+                default.backoff_base.as_secs(),
+            )),
+            backoff_max: Duration::from_secs(config::var_or(
+                "LOGIN_BACKOFF_MAX",
+                default.backoff_max.as_secs(),
+            )),
+            lockout: Duration::from_secs(config::var_or(
+                "LOGIN_LOCKOUT_DURATION",
+                default.lockout.as_secs(),
+            )),
+            window: Duration::from_secs(config::var_or(
+                "LOGIN_FAILURE_WINDOW",
+                default.window.as_secs(),
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Key {
+    Ip(String),
+    Username(String),
+}
+
+struct Attempts {
+    failures: u32,
+    last_failure: Instant,
+    blocked_until: Option<Instant>,
+}
+
+struct Entries {
+    attempts: HashMap<Key, Attempts>,
+    /// The size at which the map is pruned next, twice what was left after
+    /// the last pruning so that its cost is spread over the insertions
+    prune_at: usize,
+}
+
+/// Failed login attempts, shared by every worker
+#[derive(Clone)]
+pub struct LoginThrottle {
+    limits: Limits,
+    entries: Arc<Mutex<Entries>>,
+}
+
+impl LoginThrottle {
+    pub fn new(limits: Limits) -> Self {
+        LoginThrottle {
+            limits,
+            entries: Arc::new(Mutex::new(Entries {
+                attempts: HashMap::new(),
+                prune_at: PRUNE_THRESHOLD,
+            })),
+        }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(Limits::from_env())
+    }
+
+    /// Check whether a login attempt may go ahead, counting it as failed
+    /// until `succeeded` or `released` is called for it
+    pub fn check(&self, ip: Option<&str>, username: &str) -> Result<(), ServerError> {
+        self.attempt_at(&keys(ip, username), Instant::now())
+    }
+
+    /// Take back an attempt that succeeded and forget the failures of its
+    /// username
+    ///
+    /// Failures of the IP are kept, otherwise logging into one account would
+    /// reset the limit for guessing at all the others.
+    pub fn succeeded(&self, ip: Option<&str>, username: &str) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries
+                .attempts
+                .remove(&Key::Username(validation::username_key(username)));
+            if let Some(ip) = ip {
+                self.take_back(&mut entries, &Key::Ip(ip.to_string()));
+            }
+        }
+    }
+
+    /// Take back an attempt that failed for another reason than its
+    /// credentials, like the database being unreachable
+    pub fn released(&self, ip: Option<&str>, username: &str) {
+        if let Ok(mut entries) = self.entries.lock() {
+            for key in &keys(ip, username) {
+                self.take_back(&mut entries, key);
+            }
+        }
+    }
+
+    /// Check and count an attempt at once
+    fn attempt_at(&self, keys: &[Key], now: Instant) -> Result<(), ServerError> {
+        let mut entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(_) => return Ok(()),
+        };
+
+        self.blocked(&entries, keys, now)?;
+        self.count(&mut entries, keys, now);
+        Ok(())
+    }
+
+    fn check_at(&self, keys: &[Key], now: Instant) -> Result<(), ServerError> {
+        match self.entries.lock() {
+            Ok(entries) => self.blocked(&entries, keys, now),
+            Err(_) => Ok(()),
+        }
+    }
+
+    fn failed_at(&self, keys: &[Key], now: Instant) {
+        if let Ok(mut entries) = self.entries.lock() {
+            self.count(&mut entries, keys, now);
+        }
+    }
+
+    fn blocked(&self, entries: &Entries, keys: &[Key], now: Instant) -> Result<(), ServerError> {
+        let blocked_until = keys
+            .iter()
+            .filter_map(|key| {
+                entries
+                    .attempts
+                    .get(key)
+                    .and_then(|attempts| attempts.blocked_until)
+            }).filter(|&until| until > now)
+            .max();
+
+        match blocked_until {
+            Some(until) => Err(ServerError::LoginLocked {
+                retry_after: seconds_rounded_up(until - now),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn count(&self, entries: &mut Entries, keys: &[Key], now: Instant) {
+        if entries.attempts.len() >= entries.prune_at {
+            let window = self.limits.window;
+            entries.attempts.retain(|_, attempts| {
+                now - attempts.last_failure < window
+                    || attempts.blocked_until.map_or(false, |until| until > now)
+            });
+            entries.prune_at = cmp::max(PRUNE_THRESHOLD, entries.attempts.len() * 2);
+        }
+
+        for key in keys {
+            let attempts = entries.attempts.entry(key.clone()).or_insert(Attempts {
+                failures: 0,
+                last_failure: now,
+                blocked_until: None,
+            });
+
+            if now - attempts.last_failure > self.limits.window {
+                attempts.failures = 0;
+            }
+            attempts.failures += 1;
+            attempts.last_failure = now;
+            attempts.blocked_until = self.block(key, attempts.failures).map(|delay| now + delay);
+        }
+    }
+
+    /// Undo counting an attempt as failed
+    fn take_back(&self, entries: &mut Entries, key: &Key) {
+        let forget = match entries.attempts.get_mut(key) {
+            Some(attempts) => {
+                attempts.failures = attempts.failures.saturating_sub(1);
+                attempts.blocked_until = self
+                    .block(key, attempts.failures)
+                    .map(|delay| attempts.last_failure + delay);
+                attempts.failures == 0
+            }
+            None => false,
+        };
+        if forget {
+            entries.attempts.remove(key);
+        }
+    }
+
+    /// How long to block after the given number of failures
+    fn block(&self, key: &Key, failures: u32) -> Option<Duration> {
+        let lockout = match *key {
+            Key::Ip(_) => self.limits.ip_lockout,
+            Key::Username(_) => self.limits.username_lockout,
+        };
+
+        if failures >= lockout {
+            Some(self.limits.lockout)
+        } else if failures > self.limits.free_attempts {
+            let exponent = cmp::min(failures - self.limits.free_attempts - 1, 16);
+            Some(cmp::min(
+                self.limits.backoff_base * 2u32.pow(exponent),
+                self.limits.backoff_max,
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+fn keys(ip: Option<&str>, username: &str) -> Vec<Key> {
+    let mut keys = vec![Key::Username(validation::username_key(username))];
+    if let Some(ip) = ip {
+        keys.push(Key::Ip(ip.to_string()));
+    }
+    keys
+}
+
+fn seconds_rounded_up(duration: Duration) -> u64 {
+    duration.as_secs() + if duration.subsec_nanos() > 0 { 1 } else { 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retry_after(result: Result<(), ServerError>) -> Option<u64> {
+        match result {
+            Ok(()) => None,
+            Err(ServerError::LoginLocked { retry_after }) => Some(retry_after),
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn backs_off_exponentially_then_locks_out() {
+        let throttle = LoginThrottle::new(Limits::default());
+        let keys = keys(None, "steve");
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            throttle.failed_at(&keys, now);
+        }
+        assert_eq!(retry_after(throttle.check_at(&keys, now)), None);
+
+        let mut expected = Vec::new();
+        for _ in 3..10 {
+            throttle.failed_at(&keys, now);
+            expected.push(retry_after(throttle.check_at(&keys, now)));
+        }
+        assert_eq!(
+            expected,
+            vec![Some(1), Some(2), Some(4), Some(8), Some(16), Some(32), Some(900)]
+        );
+
+        let later = now + Duration::from_secs(900);
+        assert_eq!(retry_after(throttle.check_at(&keys, later)), None);
+    }
+
+    #[test]
+    fn usernames_and_ips_are_counted_separately() {
+        let throttle = LoginThrottle::new(Limits {
+            ip_lockout: 5,
+            ..Limits::default()
+        });
+        let now = Instant::now();
+
+        for i in 0..5 {
+            throttle.failed_at(&keys(Some("10.0.0.1"), &format!("user{}", i)), now);
+        }
+
+        // Every username only failed once, but the IP reached its lockout
+        assert_eq!(
+            retry_after(throttle.check_at(&keys(None, "user0"), now)),
+            None
+        );
+        assert_eq!(
+            retry_after(throttle.check_at(&keys(Some("10.0.0.1"), "someone"), now)),
+            Some(900)
+        );
+        assert_eq!(
+            retry_after(throttle.check_at(&keys(Some("10.0.0.2"), "someone"), now)),
+            None
+        );
+    }
+
+    #[test]
+    fn success_resets_the_username() {
+        let throttle = LoginThrottle::new(Limits::default());
+        let keys = keys(None, "Steve");
+        let now = Instant::now();
+
+        for _ in 0..5 {
+            throttle.failed_at(&keys, now);
+        }
+        assert!(throttle.check_at(&keys, now).is_err());
+
+        throttle.succeeded(None, "steve");
+        assert!(throttle.check_at(&keys, now).is_ok());
+    }
+
+    #[test]
+    fn attempts_count_until_they_succeed() {
+        let throttle = LoginThrottle::new(Limits::default());
+        let keys = keys(Some("10.0.0.1"), "steve");
+        let now = Instant::now();
+
+        // Attempts in flight together, none of which has failed yet
+        let attempts = (0..5)
+            .map(|_| retry_after(throttle.attempt_at(&keys, now)))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![None, None, None, None, Some(1)], attempts);
+
+        for _ in 0..4 {
+            throttle.released(Some("10.0.0.1"), "steve");
+        }
+        assert!(throttle.check_at(&keys, now).is_ok());
+        assert!(throttle.entries.lock().unwrap().attempts.is_empty());
+    }
+
+    #[test]
+    fn success_keeps_no_failure_of_the_ip() {
+        let throttle = LoginThrottle::new(Limits {
+            ip_lockout: 1,
+            ..Limits::default()
+        });
+        let keys = keys(Some("10.0.0.1"), "steve");
+        let now = Instant::now();
+
+        throttle.attempt_at(&keys, now).unwrap();
+        assert!(throttle.check_at(&keys, now).is_err());
+        throttle.succeeded(Some("10.0.0.1"), "steve");
+        assert!(throttle.check_at(&keys, now).is_ok());
+    }
+}
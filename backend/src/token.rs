@@ -1,14 +1,160 @@
-use super::ServerError;
+use super::{config, ServerError};
 use failure::Error;
 use jsonwebtoken::{self, Header, Validation};
+use openssl::{rand, sha};
+use std::{
+    collections::HashMap,
+    env, fs,
+    time::{Duration, SystemTime},
+};
 use time;
-use uuid::Uuid;
 
 lazy_static! {
-    static ref SECRET: String = Uuid::new_v4().to_string();
+    static ref KEYS: KeyRing =
+        KeyRing::from_env().expect("Expected JWT signing keys to be configured");
 }
 
-/// A web token
+/// A secret used to sign and verify tokens
+struct SigningKey {
+    secret: Vec<u8>,
+    /// Retired keys are kept around only so they can be reported, tokens
+    /// signed with them no longer verify
+    retired: bool,
+}
+
+/// The set of keys tokens can be signed and verified with
+///
+/// Every key is identified by the `kid` header of the tokens it signs. New
+/// tokens are always signed with the current key, while any other key that
+/// has not been retired keeps verifying, so tokens issued before a rotation
+/// stay valid until they expire.
+///
+/// Keys are read from the file named by `JWT_KEYS_FILE` (one `kid=secret`
+/// per line, `#` starts a comment) or, if that is not set, from `JWT_KEYS`
+/// (`kid=secret` pairs separated by commas). `JWT_CURRENT_KEY` selects the
+/// signing key and defaults to the last key listed. `JWT_RETIRED_KEYS` is a
+/// comma separated list of key ids that must no longer be accepted.
+pub struct KeyRing {
+    current: String,
+    keys: HashMap<String, SigningKey>,
+}
+
+impl KeyRing {
+    pub fn from_env() -> Result<Self, Error> {
+        let entries = match env::var("JWT_KEYS_FILE") {
+            Ok(path) => keys_from_file(&fs::read_to_string(&path)?),
+            Err(_) => keys_from_list(
+                &env::var("JWT_KEYS")
+                    .map_err(|_| format_err!("either JWT_KEYS_FILE or JWT_KEYS must be set"))?,
+            ),
+        };
+
+        Self::new(
+            &entries,
+            env::var("JWT_CURRENT_KEY").ok(),
+            &env::var("JWT_RETIRED_KEYS").unwrap_or_default(),
+        )
+    }
+
+    /// Build a ring from `kid=secret` entries, `retired` being a comma
+    /// separated list of key ids
+    fn new(entries: &[String], current: Option<String>, retired: &str) -> Result<Self, Error> {
+        let mut ring = KeyRing {
+            current: String::new(),
+            keys: HashMap::new(),
+        };
+
+        for entry in entries {
+            let mut parts = entry.splitn(2, '=');
+            let kid = parts.next().unwrap_or("").trim();
+            let secret = parts.next().unwrap_or("").trim();
+            if kid.is_empty() || secret.is_empty() {
+                bail!("signing keys must be given as `kid=secret`");
+            }
+            ring.insert(kid, secret.as_bytes());
+        }
+
+        if let Some(current) = current {
+            ring.current = current;
+        }
+
+        for kid in retired.split(',').map(str::trim).filter(|k| !k.is_empty()) {
+            ring.retire(kid)?;
+        }
+
+        match ring.keys.get(&ring.current) {
+            Some(key) if !key.retired => Ok(ring),
+            Some(_) => bail!("the current signing key `{}` is retired", ring.current),
+            None => bail!("no signing key named `{}` is configured", ring.current),
+        }
+    }
+
+    /// Stop accepting tokens signed with the given key
+    ///
+    /// The current key cannot be retired, rotate to a new key first.
+    fn retire(&mut self, kid: &str) -> Result<(), Error> {
+        if kid == self.current {
+            bail!("the current signing key `{}` cannot be retired", kid);
+        }
+
+        match self.keys.get_mut(kid) {
+            Some(key) => {
+                key.retired = true;
+                Ok(())
+            }
+            None => bail!("no signing key named `{}` is configured", kid),
+        }
+    }
+
+    /// Add a key, the last one added being current unless chosen otherwise
+    fn insert(&mut self, kid: &str, secret: &[u8]) {
+        self.keys.insert(
+            kid.to_string(),
+            SigningKey {
+                secret: secret.to_vec(),
+                retired: false,
+            },
+        );
+        self.current = kid.to_string();
+    }
+
+    fn signing_key(&self) -> (&str, &[u8]) {
+        // `from_env` guarantees the current key exists and is active
+        (
+            self.current.as_str(),
+            self.keys[&self.current].secret.as_slice(),
+        )
+    }
+
+    fn verifying_key(&self, kid: &str) -> Option<&[u8]> {
+        self.keys
+            .get(kid)
+            .filter(|key| !key.retired)
+            .map(|key| key.secret.as_ref())
+    }
+}
+
+/// The entries of a keys file, one per line with `#` starting a comment
+fn keys_from_file(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect()
+}
+
+/// The entries of a comma separated list
+fn keys_from_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(|entry| entry.trim().to_string())
+        .filter(|entry| !entry.is_empty())
+        .collect()
+}
+
+/// A short lived access token
+///
+/// Access tokens are validated statelessly, a client trades its refresh token
+/// for a new one when it expires.
 #[derive(Serialize, Deserialize)]
 pub struct Token {
     /// The subject of the token
@@ -17,28 +163,127 @@ pub struct Token {
     exp: i64,
     /// The time the token was issued at
     iat: i64,
-    /// Unique ID for the token
+    /// ID of the session the token was issued for
     jti: String,
 }
 
 impl Token {
-    pub fn create(user_id: i32) -> Result<String, Error> {
-        const TOKEN_LIFETIME: i64 = 3600;
+    pub fn create(user_id: i32, session_id: &str) -> Result<String, Error> {
+        let lifetime = config::var_or("ACCESS_TOKEN_LIFETIME", 900_i64);
         let claims = Token {
             sub: user_id,
-            exp: time::get_time().sec + TOKEN_LIFETIME,
+            exp: time::get_time().sec + lifetime,
             iat: time::get_time().sec,
-            jti: Uuid::new_v4().to_string(),
+            jti: session_id.to_string(),
         };
 
-        jsonwebtoken::encode(&Header::default(), &claims, SECRET.as_ref())
+        let (kid, secret) = KEYS.signing_key();
+        let mut header = Header::default();
+        header.kid = Some(kid.to_string());
+
+        jsonwebtoken::encode(&header, &claims, secret)
             .map_err(|_| Error::from(ServerError::CreateToken))
     }
 
-    pub fn verify(token: &str) -> Result<(String, i32), Error> {
-        let data = jsonwebtoken::decode::<Token>(token, SECRET.as_ref(), &Validation::default())
-            .map_err(|_| Error::from(ServerError::VerifyToken))?;
-        let token = Self::create(data.claims.sub)?;
-        Ok((token, data.claims.sub))
+    pub fn verify(token: &str) -> Result<Token, Error> {
+        let header =
+            jsonwebtoken::decode_header(token).map_err(|_| Error::from(ServerError::VerifyToken))?;
+        let kid = header.kid.ok_or(ServerError::VerifyToken)?;
+
+        let secret = KEYS.verifying_key(&kid).ok_or(ServerError::VerifyToken)?;
+        jsonwebtoken::decode::<Token>(token, secret, &Validation::default())
+            .map(|data| data.claims)
+            .map_err(|_| Error::from(ServerError::VerifyToken))
+    }
+
+    pub fn user_id(&self) -> i32 {
+        self.sub
+    }
+
+    pub fn session_id(&self) -> &str {
+        &self.jti
+    }
+}
+
+/// The tokens handed out when a session is started or refreshed
+pub struct SessionTokens {
+    pub access: String,
+    pub refresh: String,
+}
+
+/// When a refresh token issued now stops being accepted
+pub fn refresh_token_expiry() -> SystemTime {
+    let lifetime = config::var_or("REFRESH_TOKEN_LIFETIME", 30 * 24 * 3600);
+    SystemTime::now() + Duration::from_secs(lifetime)
+}
+
+/// Generate a new opaque refresh token
+pub fn new_refresh_token() -> Result<String, Error> {
+    let mut bytes = [0; 32];
+    rand::rand_bytes(&mut bytes).map_err(|_| ServerError::CreateToken)?;
+    Ok(to_hex(&bytes))
+}
+
+/// Refresh tokens are only ever stored hashed
+pub fn hash_refresh_token(token: &str) -> String {
+    to_hex(&sha::sha256(token.as_bytes()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(list: &str) -> Vec<String> {
+        keys_from_list(list)
+    }
+
+    #[test]
+    fn keys_are_read_from_lists_and_files() {
+        assert_eq!(vec!["a=1", "b=2"], keys_from_list(" a=1, ,b=2 ,"));
+        assert_eq!(
+            vec!["a=1", "b=2"],
+            keys_from_file("# keys\na=1 # old\n\n  b=2\n")
+        );
+    }
+
+    #[test]
+    fn malformed_keys_are_rejected() {
+        for list in &["a", "a=", "=1", ""] {
+            assert!(KeyRing::new(&entries(list), None, "").is_err(), "{:?}", list);
+        }
+    }
+
+    #[test]
+    fn the_last_key_listed_is_current_by_default() {
+        let ring = KeyRing::new(&entries("a=1,b=2"), None, "").unwrap();
+        assert_eq!(("b", &b"2"[..]), ring.signing_key());
+        assert_eq!(Some(&b"1"[..]), ring.verifying_key("a"));
+    }
+
+    #[test]
+    fn the_current_key_can_be_chosen() {
+        let ring = KeyRing::new(&entries("a=1,b=2"), Some("a".to_string()), "").unwrap();
+        assert_eq!(("a", &b"1"[..]), ring.signing_key());
+
+        assert!(KeyRing::new(&entries("a=1"), Some("c".to_string()), "").is_err());
+    }
+
+    #[test]
+    fn retired_keys_no_longer_verify() {
+        let ring = KeyRing::new(&entries("a=1,b=2,c=3"), None, "a, b").unwrap();
+        assert_eq!(None, ring.verifying_key("a"));
+        assert_eq!(None, ring.verifying_key("b"));
+        assert_eq!(Some(&b"3"[..]), ring.verifying_key("c"));
+        assert_eq!(None, ring.verifying_key("unknown"));
+    }
+
+    #[test]
+    fn the_current_key_cannot_be_retired() {
+        assert!(KeyRing::new(&entries("a=1,b=2"), None, "b").is_err());
+        assert!(KeyRing::new(&entries("a=1,b=2"), None, "c").is_err());
     }
 }
@@ -0,0 +1,241 @@
+//! Username and password policies applied when users register, and the
+//! rules room names follow.
+//!
+//! Usernames are normalized to NFKC before they are stored. Uniqueness is
+//! decided on a separate key that is additionally case folded, so `Steve`
+//! and `steve` cannot both be registered, and either can be used to log in.
+
+use super::{config, ServerError};
+use openssl::sha;
+use unicode_normalization::UnicodeNormalization;
+
+#[derive(Clone, Debug)]
+pub struct Policy {
+    /// Length limits of a username, in characters
+    pub username_min: usize,
+    pub username_max: usize,
+    /// Only allow ASCII letters and digits in usernames
+    pub username_ascii: bool,
+    /// Characters besides letters and digits allowed in usernames
+    pub username_symbols: String,
+    /// Length limits of a password, in bytes
+    pub password_min: usize,
+    pub password_max: usize,
+    /// How many of lowercase, uppercase, digits and other characters a
+    /// password has to mix
+    pub password_classes: usize,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            username_min: 3,
+            username_max: 32,
+            username_ascii: false,
+            username_symbols: "_-.".to_string(),
+            password_min: 8,
+            // bcrypt ignores everything past 72 bytes
+            password_max: 72,
+            password_classes: 2,
+        }
+    }
+}
+
+impl Policy {
+    pub fn from_env() -> Self {
+        let default = Policy::default();
+        Policy {
+            username_min: config::var_or("USERNAME_MIN_LENGTH", default.username_min),
+            username_max: config::var_or("USERNAME_MAX_LENGTH", default.username_max),
+            username_ascii: config::var_or("USERNAME_ASCII_ONLY", default.username_ascii),
+            username_symbols: config::var_or("USERNAME_SYMBOLS", default.username_symbols),
+            password_min: config::var_or("PASSWORD_MIN_LENGTH", default.password_min),
+            password_max: config::var_or("PASSWORD_MAX_LENGTH", default.password_max),
+            password_classes: config::var_or("PASSWORD_MIN_CLASSES", default.password_classes),
+        }
+    }
+
+    /// Check a username against the policy, returning it normalized
+    pub fn username(&self, username: &str) -> Result<String, ServerError> {
+        let username = username.trim().nfkc().collect::<String>();
+        let length = username.chars().count();
+
+        if length < self.username_min || length > self.username_max {
+            return Err(ServerError::UsernameInvalid {
+                reason: format!(
+                    "must be between {} and {} characters long",
+                    self.username_min, self.username_max
+                ),
+            });
+        }
+
+        let allowed = |c: char| {
+            let alphanumeric = if self.username_ascii {
+                c.is_ascii_alphanumeric()
+            } else {
+                c.is_alphanumeric()
+            };
+            alphanumeric || self.username_symbols.contains(c)
+        };
+
+        if !username.chars().all(allowed) {
+            return Err(ServerError::UsernameInvalid {
+                reason: if self.username_symbols.is_empty() {
+                    "may only contain letters and digits".to_string()
+                } else {
+                    format!(
+                        "may only contain letters, digits and {}",
+                        self.username_symbols
+                    )
+                },
+            });
+        }
+
+        Ok(username)
+    }
+
+    /// Check that a password is strong enough for the given username
+    pub fn password(&self, password: &str, username: &str) -> Result<(), ServerError> {
+        if password.len() < self.password_min || password.len() > self.password_max {
+            return Err(ServerError::PasswordTooWeak {
+                reason: format!(
+                    "must be between {} and {} bytes long",
+                    self.password_min, self.password_max
+                ),
+            });
+        }
+
+        let classes = [
+            password.chars().any(char::is_lowercase),
+            password.chars().any(char::is_uppercase),
+            password.chars().any(char::is_numeric),
+            password.chars().any(|c| !c.is_alphanumeric()),
+        ];
+        if classes.iter().filter(|&&class| class).count() < self.password_classes {
+            return Err(ServerError::PasswordTooWeak {
+                reason: format!(
+                    "must mix at least {} of lowercase letters, uppercase letters, digits and symbols",
+                    self.password_classes
+                ),
+            });
+        }
+
+        if username_key(password).contains(&username_key(username)) {
+            return Err(ServerError::PasswordTooWeak {
+                reason: "must not contain the username".to_string(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// The key two usernames are compared by
+pub fn username_key(username: &str) -> String {
+    username
+        .trim()
+        .nfkc()
+        .collect::<String>()
+        .to_lowercase()
+        .nfkc()
+        .collect()
+}
+
+/// What a username is logged as
+///
+/// A prefix of the SHA-256 of its key: attempts on the same account can be
+/// told apart in the logs without the logs naming it.
+pub fn username_digest(username: &str) -> String {
+    sha::sha256(username_key(username).as_bytes())[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Longest room name, in characters
+pub const ROOM_NAME_MAX: usize = 32;
+
+/// Check a room name, returning it normalized
+pub fn room_name(name: &str) -> Result<String, ServerError> {
+    let name = name.trim().nfkc().collect::<String>();
+    let length = name.chars().count();
+
+    if length == 0 || length > ROOM_NAME_MAX {
+        return Err(ServerError::RoomNameInvalid {
+            reason: format!("must be between 1 and {} characters long", ROOM_NAME_MAX),
+        });
+    }
+
+    if name.chars().any(char::is_control) {
+        return Err(ServerError::RoomNameInvalid {
+            reason: "must not contain control characters".to_string(),
+        });
+    }
+
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn room_names_are_checked() {
+        assert_eq!("rust", room_name("  rust ").unwrap());
+        assert!(room_name("   ").is_err());
+        assert!(room_name(&"a".repeat(ROOM_NAME_MAX + 1)).is_err());
+        assert!(room_name("new\nline").is_err());
+    }
+
+    #[test]
+    fn usernames_are_normalized() {
+        let policy = Policy::default();
+        assert_eq!(policy.username("  steve ").unwrap(), "steve");
+        // Fullwidth letters are folded into their ASCII form
+        assert_eq!(policy.username("ｓｔｅｖｅ").unwrap(), "steve");
+        assert_eq!(username_key("Steve"), username_key("sTEVE"));
+    }
+
+    #[test]
+    fn usernames_are_logged_as_digests() {
+        let digest = username_digest("Steve");
+        assert_eq!(16, digest.len());
+        assert!(!digest.contains("steve"));
+        assert_eq!(digest, username_digest(" ｓＴＥＶＥ"));
+        assert_ne!(digest, username_digest("steven"));
+    }
+
+    #[test]
+    fn invalid_usernames_are_rejected() {
+        let policy = Policy::default();
+        let too_long = "x".repeat(33);
+        for name in &["", "ab", "with space", "tab\there", "semi;colon", too_long.as_str()] {
+            match policy.username(name) {
+                Err(ServerError::UsernameInvalid { .. }) => (),
+                _ => panic!("{:?} should be rejected", name),
+            }
+        }
+
+        let ascii = Policy {
+            username_ascii: true,
+            ..Policy::default()
+        };
+        assert!(policy.username("stéve").is_ok());
+        assert!(ascii.username("stéve").is_err());
+    }
+
+    #[test]
+    fn weak_passwords_are_rejected() {
+        let policy = Policy::default();
+        let too_long = "a1".repeat(40);
+        for password in &["short1", "onlylowercase", "steve1234", too_long.as_str()] {
+            match policy.password(password, "steve") {
+                Err(ServerError::PasswordTooWeak { .. }) => (),
+                _ => panic!("{:?} should be rejected", password),
+            }
+        }
+
+        assert!(policy.password("correct horse", "steve").is_ok());
+        assert!(policy.password("Tr0ub4dor", "steve").is_ok());
+    }
+}
@@ -1,47 +1,100 @@
 use actix::{fut, prelude::*};
 use actix_web::{
-    ws::{Message, ProtocolError, WebsocketContext},
+    ws::{CloseCode, CloseReason, Message, ProtocolError, WebsocketContext},
     Binary,
 };
 
-use capnp::{
-    self,
-    message::{Builder, HeapAllocator, ReaderOptions},
-    serialize_packed, text,
-};
+use capnp::message::{Builder, HeapAllocator};
 
 use {
-    chatserver,
+    auth::{write_login_success, write_refresh_success, AuthFuture, Authenticated},
+    chatserver, config,
     database::executor::{
-        CreatePost, CreateSession, CreateUser, DeleteSession, FetchPosts, FindUser, FindUserID,
-        UpdateSession, UserVote,
+        CreatePost, FetchPosts, FindOrCreateRoom, ListRooms, ListSessions, RevokeSession, UserVote,
     },
+    pagination::{Cursor, Order, PageSize},
+    request::{write_response, Reply, Request},
+    session::ClientInfo,
     token::Token,
-    State,
+    validation, ServerError, State,
 };
 
-use protocol_capnp::{request, response, Vote};
+use protocol_capnp::{response, Vote};
 
-use std::default::Default;
+use std::{
+    collections::HashSet,
+    default::Default,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use failure::Error;
-use futures::future::Future;
-
+use futures::Future;
+
+/// The response to a request, built once the work it needs is done
+type WsFuture = Box<ActorFuture<Item = Builder<HeapAllocator>, Error = Error, Actor = Ws>>;
+
+/// A websocket connection
+///
+/// Requests are answered asynchronously, but one at a time: the next frame is
+/// only read once the previous request's response was sent, so responses
+/// arrive in the order their requests were made.
+///
+/// Requests are only accepted as binary Cap'n Proto frames, a text frame
+/// closes the connection. The client is pinged every `heartbeat_interval`
+/// and dropped once it was not heard from for `client_timeout`, so the chat
+/// server does not keep sending to connections that are gone.
 pub struct Ws {
-    data: Vec<u8>,
-    builder: Builder<HeapAllocator>,
+    /// Id of the chat session, once joined
     id: Option<String>,
+    /// The user the chat session was joined as
+    user_id: Option<i32>,
+    /// The rooms joined, handed to the chat server whenever the session
+    /// registers so they outlive an eviction
+    rooms: HashSet<i32>,
+    client: ClientInfo,
+    /// Frames received that could not be decoded
+    malformed: u32,
+    /// The connection is closed once this many frames could not be decoded
+    max_malformed: u32,
+    /// When the client was last heard from
+    heartbeat: Instant,
+    heartbeat_interval: Duration,
+    client_timeout: Duration,
+    page_size: PageSize,
+    hide_tallies: bool,
 }
 
 impl Default for Ws {
     fn default() -> Self {
-        Self::new()
+        Self::new(ClientInfo::default())
     }
 }
 
 impl Actor for Ws {
     type Context = WebsocketContext<Self, State>;
 
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(self.heartbeat_interval, |act, ctx| {
+            if act.heartbeat.elapsed() > act.client_timeout {
+                debug!("Websocket client stopped responding, disconnecting");
+                ctx.stop();
+                return;
+            }
+
+            ctx.ping("");
+
+            if let (Some(id), Some(user_id)) = (act.id.clone(), act.user_id) {
+                ctx.state().chat.do_send(chatserver::Heartbeat {
+                    id,
+                    addr: ctx.address().recipient(),
+                    close: ctx.address().recipient(),
+                    user_id,
+                    rooms: act.rooms.clone(),
+                });
+            }
+        });
+    }
+
     fn stopping(&mut self, ctx: &mut Self::Context) -> Running {
         // notify the chat server
         if let Some(ref id) = self.id {
@@ -59,189 +112,192 @@ impl Handler<chatserver::ServerMessage> for Ws {
     type Result = ();
 
     fn handle(&mut self, msg: chatserver::ServerMessage, ctx: &mut Self::Context) {
-        if let Some(ref id) = msg.1 {
-            if id != self.id.as_ref().unwrap() {
-                ctx.binary(msg.0);
-            }
-        } else {
-            println!("Sending binary message through websocket from server");
-            ctx.binary(msg.0);
-        }
+        trace!("Forwarding message from the chat server");
+        ctx.binary(msg.0);
+    }
+}
+
+/// The chat server ended the session, like when its account was deleted
+impl Handler<chatserver::Close> for Ws {
+    type Result = ();
+
+    fn handle(&mut self, _: chatserver::Close, ctx: &mut Self::Context) {
+        debug!("Closing a websocket whose chat session was ended");
+        // The chat server already forgot the session
+        self.id = None;
+        self.user_id = None;
+        ctx.close(Some(CloseReason {
+            code: CloseCode::Normal,
+            description: Some("The session was ended".to_string()),
+        }));
+        ctx.stop();
     }
 }
 
 impl StreamHandler<Message, ProtocolError> for Ws {
     fn handle(&mut self, msg: Message, ctx: &mut Self::Context) {
+        self.heartbeat = Instant::now();
+
         match msg {
-            Message::Text(text) => {
-                ctx.text(text);
+            Message::Text(_) => {
+                debug!("Closing a websocket that sent a text frame");
+                ctx.close(Some(CloseReason {
+                    code: CloseCode::Unsupported,
+                    description: Some("Requests are only accepted as binary frames".to_string()),
+                }));
+                ctx.stop();
             }
             Message::Binary(bin) => {
                 self.handle_request(&bin, ctx);
             }
+            Message::Ping(msg) => {
+                ctx.pong(&msg);
+            }
+            Message::Pong(_) => (),
             Message::Close(_reason) => {
                 ctx.stop();
             }
-            _ => (),
         };
     }
 }
 
 impl Ws {
-    pub fn new() -> Self {
+    pub fn new(client: ClientInfo) -> Self {
         Ws {
-            data: Vec::new(),
-            builder: Builder::new_default(),
             id: None,
+            user_id: None,
+            rooms: general_room(),
+            client,
+            malformed: 0,
+            max_malformed: config::var_or("WS_MAX_MALFORMED", 5),
+            heartbeat: Instant::now(),
+            heartbeat_interval: Duration::from_secs(config::var_or("WS_HEARTBEAT_INTERVAL", 5)),
+            client_timeout: Duration::from_secs(config::var_or("WS_CLIENT_TIMEOUT", 15)),
+            page_size: PageSize::from_env(),
+            hide_tallies: chatserver::tallies_hidden(),
         }
     }
 
     fn handle_request(&mut self, data: &Binary, ctx: &mut WebsocketContext<Self, State>) {
-        let reader = serialize_packed::read_message(&mut data.as_ref(), ReaderOptions::new())
-            .expect("Error reading message");
-
-        let request = reader
-            .get_root::<request::Reader>()
-            .expect("Error getting message root");
-
-        match request.which() {
-            Ok(request::Login(data)) => match data.which() {
-                Ok(request::login::Credentials(data)) => {
-                    match self.handle_request_login_credentials(data, ctx) {
-                        Ok(()) => self.connect_to_chat(ctx),
-                        Err(e) => {
-                            self.builder
-                                .init_root::<response::Builder>()
-                                .init_login()
-                                .set_error(&e.to_string());
-                            let _ = self.write();
-                            println!("Error: {:?}", e);
-                        }
-                    }
-
-                    self.send(ctx);
-                }
-                Ok(request::login::Token(data)) => {
-                    match self.handle_request_login_token(data, ctx) {
-                        Ok(()) => self.connect_to_chat(ctx),
-                        Err(e) => {
-                            self.builder
-                                .init_root::<response::Builder>()
-                                .init_login()
-                                .set_error(&e.to_string());
-                            let _ = self.write();
-                            println!("Error: {:?}", e);
-                        }
-                    }
-
-                    self.send(ctx);
-                }
-                Err(::capnp::NotInSchema(_)) => (),
-            },
-            Ok(request::Registration(data)) => {
-                match self.handle_request_registration(data, ctx) {
-                    Ok(()) => self.connect_to_chat(ctx),
-                    Err(e) => {
-                        self.builder
-                            .init_root::<response::Builder>()
-                            .init_login()
-                            .set_error(&e.to_string());
-                        let _ = self.write();
-                    }
-                }
-
-                self.send(ctx);
-            }
-            Ok(request::Logout(data)) => {
-                if let Err(e) = self.handle_request_logout(data, ctx) {
-                    self.builder
-                        .init_root::<response::Builder>()
-                        .init_logout()
-                        .set_error(&e.to_string());
-                    let _ = self.write();
-                }
+        let (request_id, request) = match Request::decode(data.as_ref()) {
+            Ok(decoded) => decoded,
+            Err(e) => return self.reject(&e, ctx),
+        };
 
-                self.send(ctx);
-            }
-            Ok(request::FetchPosts(data)) => {
-                match self.handle_request_fetch_posts(data, ctx) {
-                    Ok(()) => (),
-                    Err(e) => {
-                        self.builder
-                            .init_root::<response::Builder>()
-                            .init_fetch_posts()
-                            .set_error(&e.to_string());
-                        let _ = self.write();
-                    }
-                }
+        let reply = request.reply();
+        let response = self.respond(request, ctx).then(move |res, act, ctx| {
+            let mut builder = res.unwrap_or_else(|e| {
+                debug!("{:?} request failed: {}", reply, e);
+                reply.error(&e)
+            });
 
-                self.send(ctx);
+            match write_response(&mut builder, request_id) {
+                Ok(data) => ctx.binary(data),
+                Err(e) => error!("Unable to write response: {}", e),
             }
-            Ok(request::CreatePost(data)) => {
-                match self.handle_request_create_post(data, ctx) {
-                    Ok(()) => (),
-                    Err(e) => {
-                        self.builder
-                            .init_root::<response::Builder>()
-                            .init_create_post()
-                            .set_error(&e.to_string());
-                        let _ = self.write();
-                    }
-                }
 
-                self.send(ctx);
-            }
-            Ok(request::UserVote(data)) => {
-                match self.handle_request_user_vote(data, ctx) {
-                    Ok(()) => (),
-                    Err(e) => {
-                        self.builder
-                            .init_root::<response::Builder>()
-                            .init_user_vote()
-                            .set_error(&e.to_string());
-                        let _ = self.write();
-                        println!("Error: {:?}", e);
-                    }
-                }
-
-                self.send(ctx);
-            }
-            Ok(request::ConnectToChat(data)) => {
-                if let Err(e) = self.handle_request_connect_to_chat(data, ctx) {
-                    self.builder
-                        .init_root::<response::Builder>()
-                        .init_connect_to_chat()
-                        .set_error(());
-                    let _ = self.write();
-                }
+            // Pongs are not read while the request is handled, so the time
+            // spent on it does not count against the client
+            act.heartbeat = Instant::now();
+            fut::ok(())
+        });
 
-                self.send(ctx);
-            }
-            Err(::capnp::NotInSchema(_)) => (),
-        }
+        // Hold back further frames until this response is sent
+        ctx.wait(response);
     }
 
-    fn write(&mut self) -> Result<(), Error> {
-        self.data.clear();
+    /// Answer a frame that could not be decoded, closing the connection once
+    /// the client sent too many of them
+    fn reject(&mut self, e: &Error, ctx: &mut WebsocketContext<Self, State>) {
+        self.malformed += 1;
+        warn!(
+            "Rejected a request from {} ({} of {}): {}",
+            self.client.ip_address.as_ref().map_or("unknown", |ip| ip.as_str()),
+            self.malformed,
+            self.max_malformed,
+            e
+        );
+
+        match write_response(&mut Reply::Invalid.error(e), 0) {
+            Ok(data) => ctx.binary(data),
+            Err(e) => error!("Unable to write response: {}", e),
+        }
 
-        serialize_packed::write_message(&mut self.data, &self.builder)?;
-        Ok(())
+        if self.malformed >= self.max_malformed {
+            ctx.close(Some(CloseReason {
+                code: CloseCode::Policy,
+                description: Some("Too many malformed requests".to_string()),
+            }));
+            ctx.stop();
+        }
     }
 
-    fn send(&self, ctx: &mut WebsocketContext<Self, State>) {
-        ctx.binary(self.data.clone());
+    fn respond(&mut self, request: Request, ctx: &mut WebsocketContext<Self, State>) -> WsFuture {
+        match request {
+            Request::LoginCredentials { username, password } => {
+                self.handle_request_login_credentials(username, password, ctx)
+            }
+            Request::LoginToken(token) => self.handle_request_login_token(&token, ctx),
+            Request::Registration { username, password } => {
+                self.handle_request_registration(username, password, ctx)
+            }
+            Request::Logout(token) => self.handle_request_logout(&token, ctx),
+            Request::LogoutAll(token) => self.handle_request_logout_all(&token, ctx),
+            Request::ListSessions(token) => self.handle_request_list_sessions(&token, ctx),
+            Request::RevokeSession { token, session_id } => {
+                self.handle_request_revoke_session(&token, session_id, ctx)
+            }
+            Request::ChangePassword {
+                token,
+                current_password,
+                new_password,
+            } => self.handle_request_change_password(&token, current_password, new_password, ctx),
+            Request::DeleteAccount { token, password } => {
+                self.handle_request_delete_account(&token, password, ctx)
+            }
+            Request::FetchPosts {
+                token,
+                room_id,
+                cursor,
+                limit,
+                order,
+            } => self.handle_request_fetch_posts(&token, room_id, &cursor, limit, order, ctx),
+            Request::CreatePost {
+                token,
+                content,
+                room_id,
+            } => self.handle_request_create_post(&token, content, room_id, ctx),
+            Request::UserVote {
+                token,
+                vote,
+                post_id,
+            } => self.handle_request_user_vote(&token, vote, post_id, ctx),
+            Request::ConnectToChat(token) => self.handle_request_connect_to_chat(&token, ctx),
+            Request::RefreshSession(token) => self.handle_request_refresh_session(token, ctx),
+            Request::JoinRoom { token, name } => self.handle_request_join_room(&token, &name, ctx),
+            Request::LeaveRoom { token, room_id } => {
+                self.handle_request_leave_room(&token, room_id, ctx)
+            }
+            Request::ListRooms(token) => self.handle_request_list_rooms(&token, ctx),
+        }
     }
 
-    fn connect_to_chat(&self, ctx: &mut WebsocketContext<Self, State>) {
+    fn connect_to_chat(&self, user_id: i32, ctx: &mut WebsocketContext<Self, State>) {
         let addr = ctx.address();
         ctx.state()
             .chat
             .send(chatserver::Connect {
-                addr: addr.recipient(),
+                addr: addr.clone().recipient(),
+                close: addr.recipient(),
+                user_id,
+                rooms: self.rooms.clone(),
             }).into_actor(self)
-            .then(|res, act, ctx| {
+            .then(move |res, act, ctx| {
                 match res {
-                    Ok(res) => act.id = Some(res),
+                    Ok(res) => {
+                        act.id = Some(res);
+                        act.user_id = Some(user_id);
+                    }
                     // something is wrong with chat server
                     _ => ctx.stop(),
                 }
@@ -249,281 +305,530 @@ impl Ws {
             }).wait(ctx);
     }
 
+    /// Leave the chat until the next login, which connects to it again
+    fn disconnect_from_chat(&mut self, ctx: &mut WebsocketContext<Self, State>) {
+        if let Some(id) = self.id.take() {
+            ctx.state().chat.do_send(chatserver::Disconnect { id });
+        }
+        self.user_id = None;
+        self.rooms = general_room();
+    }
+
     fn handle_request_login_credentials(
         &mut self,
-        data: request::login::credentials::Reader,
+        username: String,
+        password: String,
         ctx: &mut WebsocketContext<Self, State>,
-    ) -> Result<(), Error> {
-        let name = data.get_username()?;
-        let password = data.get_password()?;
-        println!("Name: {} \nPassword: {}", name, password);
+    ) -> WsFuture {
+        debug!("Login attempt for {}", validation::username_digest(&username));
 
-        let user = ctx
+        let login = ctx
             .state()
-            .db
-            .send(FindUser {
-                username: name.to_string(),
-                password: password.to_string(),
-            }).wait()??;
-
-        match user {
-            Some(user) => {
-                let token = ctx
-                    .state()
-                    .db
-                    .send(CreateSession {
-                        id: Token::create(user.id)?,
-                    }).wait()??;
-
-                let mut success = self
-                    .builder
-                    .init_root::<response::Builder>()
-                    .init_login()
-                    .init_success();
-
-                success.set_token(&token.id);
-
-                let mut u = success.init_user();
-                u.set_id(user.id);
-                u.set_username(&user.username);
-                u.set_karma(user.karma);
-                u.set_streak(user.streak);
-            }
-            None => {
-                return Err(super::ServerError::FindUser.into());
-            }
-        }
+            .auth
+            .login(username, password, self.client.clone());
 
-        self.write()
+        self.logged_in(login)
     }
 
     fn handle_request_login_token(
         &mut self,
-        data: Result<text::Reader, capnp::Error>,
+        token: &str,
         ctx: &mut WebsocketContext<Self, State>,
-    ) -> Result<(), Error> {
-        let token = data?;
-        println!("Renewing Token: {} \n", token);
-
-        let (new_id, user_id) = Token::verify(token)?;
+    ) -> WsFuture {
+        debug!("Resuming session with an access token");
 
-        let new_token = ctx
-            .state()
-            .db
-            .send(UpdateSession {
-                old_id: token.to_string(),
-                new_id,
-            }).wait()??;
-
-        let user = ctx.state().db.send(FindUserID { user_id }).wait()??;
-
-        match user {
-            Some(user) => {
-                let mut success = self
-                    .builder
-                    .init_root::<response::Builder>()
-                    .init_login()
-                    .init_success();
-
-                success.set_token(&new_token.id);
-
-                let mut u = success.init_user();
-                u.set_id(user.id);
-                u.set_username(&user.username);
-                u.set_karma(user.karma);
-                u.set_streak(user.streak);
-            }
-            None => {
-                return Err(super::ServerError::FindUser.into());
-            }
-        }
+        let resume = ctx.state().auth.resume(token);
 
-        self.write()
+        self.logged_in(resume)
     }
 
     fn handle_request_registration(
         &mut self,
-        data: request::registration::Reader,
+        username: String,
+        password: String,
         ctx: &mut WebsocketContext<Self, State>,
-    ) -> Result<(), Error> {
-        let username = data.get_username()?.to_string();
-        let password = data.get_password()?.to_string();
-        let user = ctx
+    ) -> WsFuture {
+        let register = ctx
             .state()
-            .db
-            .send(CreateUser { username, password })
-            .wait()??;
-        {
-            let mut success = self
-                .builder
-                .init_root::<response::Builder>()
-                .init_login()
-                .init_success();
-            success.set_token(&Token::create(user.id)?);
-
-            let mut u = success.init_user();
-            u.set_id(user.id);
-            u.set_username(&user.username);
-            u.set_karma(user.karma);
-            u.set_streak(user.streak);
-        }
+            .auth
+            .register(username, password, self.client.clone());
+
+        self.logged_in(register)
+    }
+
+    /// Answer a successful login and join the chat as its user
+    fn logged_in(&self, login: AuthFuture<Authenticated>) -> WsFuture {
+        Box::new(login.into_actor(self).map(|authenticated, act, ctx| {
+            act.connect_to_chat(authenticated.user.id, ctx);
 
-        self.write()
+            let mut builder = Builder::new_default();
+            write_login_success(&mut builder, &authenticated);
+            builder
+        }))
     }
 
     fn handle_request_logout(
         &mut self,
-        data: Result<text::Reader, capnp::Error>,
+        token: &str,
         ctx: &mut WebsocketContext<Self, State>,
-    ) -> Result<(), Error> {
-        let token = data?;
-        ctx.state()
-            .db
-            .send(DeleteSession {
-                session_id: token.to_string(),
-            }).wait()??;
-
-        self.builder
-            .init_root::<response::Builder>()
-            .init_logout()
-            .set_success(());
-        self.write()
+    ) -> WsFuture {
+        let logout = ctx
+            .state()
+            .auth
+            .logout(token)
+            .into_actor(self)
+            .map(|(), act, ctx| {
+                act.disconnect_from_chat(ctx);
+                success(|res| res.init_logout().set_success(()))
+            });
+
+        Box::new(logout)
     }
 
-    fn handle_request_fetch_posts(
+    fn handle_request_logout_all(
+        &mut self,
+        token: &str,
+        ctx: &mut WebsocketContext<Self, State>,
+    ) -> WsFuture {
+        let logout = ctx
+            .state()
+            .auth
+            .logout_all(token)
+            .into_actor(self)
+            .map(|(), act, ctx| {
+                act.disconnect_from_chat(ctx);
+                success(|res| res.init_logout().set_success(()))
+            });
+
+        Box::new(logout)
+    }
+
+    fn handle_request_list_sessions(
         &mut self,
-        data: Result<text::Reader, capnp::Error>,
+        token: &str,
         ctx: &mut WebsocketContext<Self, State>,
-    ) -> Result<(), Error> {
-        let token = data?;
-        let (new_token, user_id) = Token::verify(token)?;
-        let res = ctx.state().db.send(FetchPosts { user_id }).wait()??;
-        ctx.state().db.do_send(UpdateSession {
-            old_id: token.to_string(),
-            new_id: new_token.clone(),
+    ) -> WsFuture {
+        let db = ctx.state().db.clone();
+        let list = ctx.state().auth.verify(token).and_then(move |token| {
+            db.send(ListSessions {
+                user_id: token.user_id(),
+            }).from_err()
+            .and_then(|res| res)
+            .map(move |sessions| {
+                let mut builder = Builder::new_default();
+                {
+                    let mut list = builder
+                        .init_root::<response::Builder>()
+                        .init_list_sessions()
+                        .init_success(sessions.len() as u32);
+
+                    for (i, session) in sessions.iter().enumerate() {
+                        let mut s = list.reborrow().get(i as u32);
+                        s.set_id(&session.id);
+                        s.set_created_at(unix_time(session.created_at));
+                        s.set_expires_at(unix_time(session.expires_at));
+                        s.set_last_seen_at(unix_time(session.last_seen_at));
+                        if let Some(ref user_agent) = session.user_agent {
+                            s.set_user_agent(user_agent);
+                        }
+                        if let Some(ref ip_address) = session.ip_address {
+                            s.set_ip_address(ip_address);
+                        }
+                        s.set_current(session.id == token.session_id());
+                    }
+                }
+                builder
+            })
         });
 
-        {
-            let mut success = self
-                .builder
-                .init_root::<response::Builder>()
-                .init_fetch_posts()
-                .init_success();
-            success.set_token(&new_token);
-            let mut fetched_posts = success.init_posts(res.len() as u32);
-
-            for (i, (post, vote)) in res.iter().enumerate() {
-                let mut p = fetched_posts.reborrow().get(i as u32);
-                p.set_id(post.id);
-                p.set_content(&post.content);
-                p.set_valid(post.valid);
-                p.set_user_id(post.user_id);
-                let vote = match vote {
-                    None => Vote::None,
-                    Some(v) => match v.up_or_down {
-                        1 => Vote::Up,
-                        -1 => Vote::Down,
-                        _ => Vote::None,
-                    },
-                };
-                p.set_vote(vote);
-            }
-        }
-        self.write()
+        Box::new(list.into_actor(self))
     }
 
-    fn handle_request_create_post(
+    fn handle_request_revoke_session(
+        &mut self,
+        token: &str,
+        session_id: String,
+        ctx: &mut WebsocketContext<Self, State>,
+    ) -> WsFuture {
+        let db = ctx.state().db.clone();
+        let auth = ctx.state().auth.clone();
+        let revoke = auth.verify(token).and_then(move |token| {
+            db.send(RevokeSession {
+                user_id: token.user_id(),
+                session_id: session_id.clone(),
+            }).from_err()
+            .and_then(|res| res)
+            .map(move |()| {
+                auth.forget_session(&session_id);
+                success(|res| res.init_revoke_session().set_success(()))
+            })
+        });
+
+        Box::new(revoke.into_actor(self))
+    }
+
+    fn handle_request_change_password(
         &mut self,
-        data: request::create_post::Reader,
+        token: &str,
+        current_password: String,
+        new_password: String,
         ctx: &mut WebsocketContext<Self, State>,
-    ) -> Result<(), Error> {
-        let token = data.get_token()?;
-        let content = data.get_content()?.to_string();
+    ) -> WsFuture {
+        let change = ctx
+            .state()
+            .auth
+            .change_password(token, current_password, new_password, self.client.clone())
+            .map(|()| success(|res| res.init_change_password().set_success(())));
+
+        Box::new(change.into_actor(self))
+    }
 
-        let (new_token, user_id) = Token::verify(token)?;
-        let (post, _username) = ctx
+    fn handle_request_delete_account(
+        &mut self,
+        token: &str,
+        password: String,
+        ctx: &mut WebsocketContext<Self, State>,
+    ) -> WsFuture {
+        let delete = ctx
             .state()
-            .db
-            .send(CreatePost { user_id, content })
-            .wait()??;
+            .auth
+            .delete_account(token, password, self.client.clone());
+
+        Box::new(delete.into_actor(self).map(|(user_id, posts), act, ctx| {
+            act.disconnect_from_chat(ctx);
+            ctx.state()
+                .chat
+                .do_send(chatserver::DisconnectUser { user_id });
+            ctx.state().chat.do_send(chatserver::RemovePosts { posts });
+            // Handled once the response was sent, no frames are read before
+            ctx.notify(chatserver::Close);
+            success(|res| res.init_delete_account().set_success(()))
+        }))
+    }
+
+    fn handle_request_fetch_posts(
+        &mut self,
+        token: &str,
+        room_id: i32,
+        cursor: &str,
+        limit: u16,
+        order: Order,
+        ctx: &mut WebsocketContext<Self, State>,
+    ) -> WsFuture {
+        let after = if cursor.is_empty() {
+            None
+        } else {
+            match Cursor::decode(cursor) {
+                Ok(cursor) => Some(cursor),
+                Err(e) => return Box::new(fut::err(e.into())),
+            }
+        };
+        let limit = self.page_size.limit(limit);
+        let hide_tallies = self.hide_tallies;
+
+        let db = ctx.state().db.clone();
+        let fetch = ctx.state().auth.verify(token).and_then(move |token| {
+            db.send(FetchPosts {
+                user_id: token.user_id(),
+                room_id,
+                after,
+                limit,
+                order,
+            }).from_err()
+            .and_then(|res| res)
+            .map(move |page| {
+                let mut builder = Builder::new_default();
+                {
+                    let mut success = builder
+                        .init_root::<response::Builder>()
+                        .init_fetch_posts()
+                        .init_success();
+                    if let Some(next) = page.next {
+                        success.set_next_cursor(&next.encode());
+                    }
+                    let mut fetched_posts = success.init_posts(page.posts.len() as u32);
+
+                    for (i, (post, vote)) in page.posts.iter().enumerate() {
+                        let vote = match vote {
+                            None => Vote::None,
+                            Some(v) => match v.up_or_down {
+                                1 => Vote::Up,
+                                -1 => Vote::Down,
+                                _ => Vote::None,
+                            },
+                        };
+                        chatserver::write_post(
+                            fetched_posts.reborrow().get(i as u32),
+                            post,
+                            vote,
+                            hide_tallies,
+                        );
+                    }
+                }
+                builder
+            })
+        });
+
+        Box::new(fetch.into_actor(self))
+    }
 
-        ctx.state().db.do_send(UpdateSession {
-            old_id: token.to_string(),
-            new_id: new_token.clone(),
+    fn handle_request_create_post(
+        &mut self,
+        token: &str,
+        content: String,
+        room_id: i32,
+        ctx: &mut WebsocketContext<Self, State>,
+    ) -> WsFuture {
+        let db = ctx.state().db.clone();
+        let create = ctx.state().auth.verify(token).and_then(move |token| {
+            db.send(CreatePost {
+                user_id: token.user_id(),
+                content,
+                room_id,
+            }).from_err()
+            .and_then(|res| res)
         });
 
-        {
-            let mut success = self
-                .builder
-                .init_root::<response::Builder>()
-                .init_create_post()
-                .init_success();
-
-            success.set_token(&new_token);
-            let mut p = success.init_post();
-            p.set_id(post.id);
-            p.set_content(&post.content);
-            p.set_valid(post.valid);
-            p.set_user_id(post.user_id);
-            p.set_vote(Vote::None);
-        }
+        Box::new(
+            create
+                .into_actor(self)
+                .map(|(post, _username), act, ctx| {
+                    let mut builder = Builder::new_default();
+                    {
+                        let p = builder
+                            .init_root::<response::Builder>()
+                            .init_create_post()
+                            .init_success()
+                            .init_post();
+                        chatserver::write_post(p, &post, Vote::None, act.hide_tallies);
+                    }
 
-        if let Some(ref id) = self.id {
-            ctx.state().chat.do_send(chatserver::ClientMessage {
-                id: id.to_owned(),
-                msg: post,
-            });
-        }
+                    let hide_tallies = act.hide_tallies;
+                    let update = chatserver::write_update(|update| {
+                        chatserver::write_post(
+                            update.init_new_post(),
+                            &post,
+                            Vote::None,
+                            hide_tallies,
+                        )
+                    });
+                    if let Ok(data) = update {
+                        // The poster already has the post from this response
+                        let audience = match act.id {
+                            Some(ref id) => {
+                                chatserver::Audience::RoomExceptSession(post.room_id, id.to_owned())
+                            }
+                            None => chatserver::Audience::Room(post.room_id),
+                        };
+                        ctx.state()
+                            .chat
+                            .do_send(chatserver::SendTo { audience, data });
+                    }
 
-        self.write()
+                    builder
+                }),
+        )
     }
 
     fn handle_request_user_vote(
         &mut self,
-        data: request::user_vote::Reader,
+        token: &str,
+        vote: Vote,
+        post_id: i32,
         ctx: &mut WebsocketContext<Self, State>,
-    ) -> Result<(), Error> {
-        let token = data.get_token()?;
-        let vote = data.get_vote()?;
-        let post_id = data.get_post_id();
-
-        let (new_token, user_id) = Token::verify(token)?;
+    ) -> WsFuture {
         let up_or_down = match vote {
             Vote::Up => 1,
             Vote::Down => -1,
-            Vote::None => return Err(super::ServerError::InvalidVote.into()),
+            Vote::None => 0,
         };
 
-        ctx.state()
-            .db
-            .send(UserVote {
+        let db = ctx.state().db.clone();
+        let user_vote = ctx.state().auth.verify(token).and_then(move |token| {
+            db.send(UserVote {
                 post_id,
-                user_id,
+                user_id: token.user_id(),
                 up_or_down,
-            }).wait()??;
+            }).from_err()
+            .and_then(|res| res)
+        });
 
-        {
-            self.builder
-                .init_root::<response::Builder>()
-                .init_user_vote()
-                .set_success(&new_token);
-        }
+        Box::new(user_vote.into_actor(self).map(|post, act, ctx| {
+            // Hidden tallies are sent once voting on the post closes
+            if !(act.hide_tallies && post.valid) {
+                let update = chatserver::write_update(|update| {
+                    chatserver::write_tally(update.init_vote_tally(), &post)
+                });
+                if let Ok(data) = update {
+                    ctx.state().chat.do_send(chatserver::SendTo {
+                        audience: chatserver::Audience::Room(post.room_id),
+                        data,
+                    });
+                }
+            }
+            success(|res| res.init_user_vote().set_success(()))
+        }))
+    }
+
+    fn handle_request_refresh_session(
+        &mut self,
+        refresh_token: String,
+        ctx: &mut WebsocketContext<Self, State>,
+    ) -> WsFuture {
+        let refresh = ctx
+            .state()
+            .auth
+            .refresh(refresh_token, self.client.clone())
+            .map(|authenticated| {
+                let mut builder = Builder::new_default();
+                write_refresh_success(&mut builder, &authenticated);
+                builder
+            });
+
+        Box::new(refresh.into_actor(self))
+    }
+
+    fn handle_request_join_room(
+        &mut self,
+        token: &str,
+        name: &str,
+        ctx: &mut WebsocketContext<Self, State>,
+    ) -> WsFuture {
+        let name = match validation::room_name(name) {
+            Ok(name) => name,
+            Err(e) => return Box::new(fut::err(e.into())),
+        };
+        // Only a session connected to the chat can join, check before a room
+        // is created for it
+        let id = match self.id {
+            Some(ref id) => id.to_owned(),
+            None => return Box::new(fut::err(ServerError::JoinChat.into())),
+        };
+
+        let db = ctx.state().db.clone();
+        let room = self.verify_joined(token, ctx).and_then(move |_| {
+            db.send(FindOrCreateRoom { name })
+                .from_err()
+                .and_then(|res| res)
+        });
+
+        Box::new(room.into_actor(self).map(move |room, act, ctx| {
+            ctx.state().chat.do_send(chatserver::JoinRoom {
+                id,
+                room_id: room.id,
+            });
+            act.rooms.insert(room.id);
+
+            success(|res| {
+                let mut r = res.init_join_room().init_success();
+                r.set_id(room.id);
+                r.set_name(&room.name);
+            })
+        }))
+    }
 
-        self.write()
+    fn handle_request_leave_room(
+        &mut self,
+        token: &str,
+        room_id: i32,
+        ctx: &mut WebsocketContext<Self, State>,
+    ) -> WsFuture {
+        let verify = self.verify_joined(token, ctx);
+
+        Box::new(verify.into_actor(self).map(move |_, act, ctx| {
+            if let Some(ref id) = act.id {
+                ctx.state().chat.do_send(chatserver::LeaveRoom {
+                    id: id.to_owned(),
+                    room_id,
+                });
+            }
+            act.rooms.remove(&room_id);
+            success(|res| res.init_leave_room().set_success(()))
+        }))
+    }
+
+    /// Verify a token, which has to belong to the user the chat session was
+    /// joined as
+    fn verify_joined(
+        &self,
+        token: &str,
+        ctx: &mut WebsocketContext<Self, State>,
+    ) -> AuthFuture<Token> {
+        let user_id = self.user_id;
+        Box::new(ctx.state().auth.verify(token).and_then(move |token| {
+            if Some(token.user_id()) == user_id {
+                Ok(token)
+            } else {
+                Err(ServerError::VerifyToken.into())
+            }
+        }))
+    }
+
+    fn handle_request_list_rooms(
+        &mut self,
+        token: &str,
+        ctx: &mut WebsocketContext<Self, State>,
+    ) -> WsFuture {
+        let db = ctx.state().db.clone();
+        let list = ctx.state().auth.verify(token).and_then(move |_| {
+            db.send(ListRooms)
+                .from_err()
+                .and_then(|res| res)
+                .map(|rooms| {
+                    let mut builder = Builder::new_default();
+                    {
+                        let mut list = builder
+                            .init_root::<response::Builder>()
+                            .init_list_rooms()
+                            .init_success(rooms.len() as u32);
+
+                        for (i, room) in rooms.iter().enumerate() {
+                            let mut r = list.reborrow().get(i as u32);
+                            r.set_id(room.id);
+                            r.set_name(&room.name);
+                        }
+                    }
+                    builder
+                })
+        });
+
+        Box::new(list.into_actor(self))
     }
 
     fn handle_request_connect_to_chat(
         &mut self,
-        data: Result<text::Reader, capnp::Error>,
+        token: &str,
         ctx: &mut WebsocketContext<Self, State>,
-    ) -> Result<(), Error> {
-        let token = data?;
-        self.connect_to_chat(ctx);
-
-        self.builder
-            .init_root::<response::Builder>()
-            .init_connect_to_chat()
-            .set_success(());
-        self.write()
+    ) -> WsFuture {
+        let verify = ctx.state().auth.verify(token);
+
+        Box::new(verify.into_actor(self).map(|token, act, ctx| {
+            act.connect_to_chat(token.user_id(), ctx);
+            success(|res| res.init_connect_to_chat().set_success(()))
+        }))
     }
 }
+
+/// The rooms a connection starts out in
+fn general_room() -> HashSet<i32> {
+    let mut rooms = HashSet::new();
+    rooms.insert(chatserver::GENERAL_ROOM);
+    rooms
+}
+
+/// Build a response that needs no more than a few setters
+fn success<F>(init: F) -> Builder<HeapAllocator>
+where
+    F: FnOnce(response::Builder),
+{
+    let mut builder = Builder::new_default();
+    init(builder.init_root::<response::Builder>());
+    builder
+}
+
+/// Seconds since the unix epoch, as sent to clients
+fn unix_time(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
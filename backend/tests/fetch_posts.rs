@@ -0,0 +1,166 @@
+//! Queries made to fetch a page of posts.
+//!
+//! Needs `DATABASE_URL` to point to a migrated database, everything added to
+//! it is rolled back. Run with `cargo test -p backend -- --ignored`.
+
+extern crate backend;
+extern crate diesel;
+extern crate dotenv;
+
+use backend::{
+    database::{
+        executor::{fetch_posts, FetchPosts},
+        models::{NewPost, NewRoom, NewUser, Post, Room, User, Vote},
+        schema::{posts, rooms, users, votes},
+    },
+    pagination::Order,
+};
+use diesel::{
+    connection::{AnsiTransactionManager, SimpleConnection},
+    deserialize::{Queryable, QueryableByName},
+    pg::Pg,
+    prelude::*,
+    query_builder::{AsQuery, QueryFragment, QueryId},
+    sql_types::HasSqlType,
+    ConnectionResult,
+};
+use dotenv::dotenv;
+use std::{cell::Cell, env};
+
+const POSTS: usize = 3000;
+
+/// A connection counting the queries sent through it
+struct CountingConnection {
+    inner: PgConnection,
+    queries: Cell<usize>,
+}
+
+impl CountingConnection {
+    fn count(&self) {
+        self.queries.set(self.queries.get() + 1);
+    }
+}
+
+impl SimpleConnection for CountingConnection {
+    fn batch_execute(&self, query: &str) -> QueryResult<()> {
+        self.count();
+        self.inner.batch_execute(query)
+    }
+}
+
+impl Connection for CountingConnection {
+    type Backend = Pg;
+    type TransactionManager = AnsiTransactionManager;
+
+    fn establish(database_url: &str) -> ConnectionResult<Self> {
+        Ok(CountingConnection {
+            inner: PgConnection::establish(database_url)?,
+            queries: Cell::new(0),
+        })
+    }
+
+    fn execute(&self, query: &str) -> QueryResult<usize> {
+        self.count();
+        self.inner.execute(query)
+    }
+
+    fn query_by_index<T, U>(&self, source: T) -> QueryResult<Vec<U>>
+    where
+        T: AsQuery,
+        T::Query: QueryFragment<Self::Backend> + QueryId,
+        Self::Backend: HasSqlType<T::SqlType>,
+        U: Queryable<T::SqlType, Self::Backend>,
+    {
+        self.count();
+        self.inner.query_by_index(source)
+    }
+
+    fn query_by_name<T, U>(&self, source: &T) -> QueryResult<Vec<U>>
+    where
+        T: QueryFragment<Self::Backend> + QueryId,
+        U: QueryableByName<Self::Backend>,
+    {
+        self.count();
+        self.inner.query_by_name(source)
+    }
+
+    fn execute_returning_count<T>(&self, source: &T) -> QueryResult<usize>
+    where
+        T: QueryFragment<Self::Backend> + QueryId,
+    {
+        self.count();
+        self.inner.execute_returning_count(source)
+    }
+
+    fn transaction_manager(&self) -> &Self::TransactionManager {
+        self.inner.transaction_manager()
+    }
+}
+
+#[test]
+#[ignore]
+fn votes_are_fetched_in_one_query() {
+    dotenv().ok();
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let conn = CountingConnection::establish(&database_url).expect("Failed to connect");
+    conn.inner.begin_test_transaction().unwrap();
+
+    let user = diesel::insert_into(users::table)
+        .values(&NewUser {
+            username: "fetch_posts_test".to_string(),
+            password: String::new(),
+            username_key: "fetch_posts_test".to_string(),
+        }).get_result::<User>(&conn.inner)
+        .unwrap();
+    // A room of its own, so posts already in the database are not fetched
+    let room_id = diesel::insert_into(rooms::table)
+        .values(&NewRoom {
+            name: "fetch_posts_test".to_string(),
+        }).get_result::<Room>(&conn.inner)
+        .unwrap()
+        .id;
+
+    let new_posts = (0..POSTS)
+        .map(|i| NewPost {
+            content: format!("post {}", i),
+            user_id: user.id,
+            room_id,
+        }).collect::<Vec<_>>();
+    let created = diesel::insert_into(posts::table)
+        .values(&new_posts)
+        .get_results::<Post>(&conn.inner)
+        .unwrap();
+    // Every other post is voted on
+    let cast = created
+        .iter()
+        .step_by(2)
+        .map(|post| Vote {
+            user_id: user.id,
+            post_id: post.id,
+            up_or_down: 1,
+        }).collect::<Vec<_>>();
+    diesel::insert_into(votes::table)
+        .values(&cast)
+        .execute(&conn.inner)
+        .unwrap();
+
+    conn.queries.set(0);
+    let page = fetch_posts(
+        &conn,
+        &FetchPosts {
+            user_id: user.id,
+            room_id,
+            after: None,
+            limit: POSTS as u16,
+            order: Order::OldestFirst,
+        },
+    ).unwrap();
+
+    assert_eq!(2, conn.queries.get());
+    assert_eq!(POSTS, page.posts.len());
+    assert!(page.next.is_none());
+    for (i, (post, vote)) in page.posts.iter().enumerate() {
+        assert_eq!(created[i].id, post.id);
+        assert_eq!(i % 2 == 0, vote.is_some());
+    }
+}
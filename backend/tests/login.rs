@@ -0,0 +1,178 @@
+//! Requests made to `/login` over HTTP.
+//!
+//! The test logging in needs `DATABASE_URL` to point to a migrated database it
+//! may add a user to. Run it with `cargo test -p backend -- --ignored`.
+
+extern crate actix;
+extern crate actix_web;
+extern crate backend;
+extern crate capnp;
+extern crate diesel;
+extern crate dotenv;
+extern crate r2d2;
+extern crate uuid;
+
+use actix::prelude::*;
+use actix_web::{http, test::TestServer, HttpMessage};
+use backend::{
+    auth::AuthService,
+    chatserver::ChatServer,
+    database::executor::DbExecutor,
+    hasher::Hasher,
+    protocol_capnp::{request, response, ErrorCode},
+    request::MAX_REQUEST_SIZE,
+    server::login_register,
+    session::SessionCache,
+    throttle::LoginThrottle,
+    validation::Policy,
+    State,
+};
+use capnp::{
+    message::{Builder, ReaderOptions},
+    serialize_packed,
+};
+use diesel::{prelude::*, r2d2::ConnectionManager};
+use dotenv::dotenv;
+use r2d2::Pool;
+use std::env;
+use uuid::Uuid;
+
+const PASSWORD: &str = "login Passw0rd";
+
+/// Serve `/login` with the state the server would have, the pool only
+/// connecting once a request needs the database
+fn serve(database_url: &str) -> TestServer {
+    let database_url = database_url.to_string();
+    TestServer::build_with_state(move || {
+        let pool = Pool::builder()
+            .max_size(1)
+            .build_unchecked(ConnectionManager::<PgConnection>::new(database_url.clone()));
+        let db = SyncArbiter::start(1, move || DbExecutor(pool.clone()));
+        let hasher = Hasher::start_from_env().unwrap();
+        let db_clone = db.clone();
+        let chat = Arbiter::start(move |_| ChatServer::new(db_clone));
+        let auth = AuthService::new(
+            db.clone(),
+            hasher,
+            SessionCache::from_env(),
+            Policy::default(),
+            LoginThrottle::from_env(),
+        );
+        State { db, chat, auth }
+    }).start(|app| {
+        app.resource("/login", |r| r.method(http::Method::POST).f(login_register));
+    })
+}
+
+fn encode<F>(build: F) -> Vec<u8>
+where
+    F: FnOnce(request::Builder),
+{
+    let mut builder = Builder::new_default();
+    build(builder.init_root::<request::Builder>());
+    let mut data = Vec::new();
+    serialize_packed::write_message(&mut data, &builder).unwrap();
+    data
+}
+
+/// Post a request to `/login` and read back the response
+fn post<T, F>(srv: &mut TestServer, data: Vec<u8>, read: F) -> T
+where
+    F: FnOnce(response::Reader) -> T,
+{
+    let req = srv.post().uri(srv.url("/login")).body(data).unwrap();
+    let res = srv.execute(req.send()).unwrap();
+    assert!(res.status().is_success());
+    let body = srv.execute(res.body()).unwrap();
+
+    let message =
+        serialize_packed::read_message(&mut body.as_ref(), ReaderOptions::new()).unwrap();
+    read(message.get_root::<response::Reader>().unwrap())
+}
+
+#[test]
+fn failed_registrations_carry_the_request_id() {
+    // Registration is rejected before the database is needed
+    let mut srv = serve("postgres://localhost/unused");
+    let data = encode(|mut req| {
+        req.set_request_id(42);
+        let mut registration = req.init_registration();
+        registration.set_username("a");
+        registration.set_password(PASSWORD);
+    });
+
+    let (request_id, code) = post(&mut srv, data, |res| {
+        let code = match res.which().unwrap() {
+            response::Login(login) => match login.which().unwrap() {
+                response::login::Error(error) => error.unwrap().get_code().unwrap(),
+                _ => panic!("registration should fail"),
+            },
+            _ => panic!("expected a login response"),
+        };
+        (res.get_request_id(), code)
+    });
+
+    assert_eq!(42, request_id);
+    assert_eq!(ErrorCode::UsernameInvalid, code);
+}
+
+#[test]
+fn oversized_requests_are_answered_as_invalid() {
+    let mut srv = serve("postgres://localhost/unused");
+    let data = vec![0; MAX_REQUEST_SIZE + 1];
+
+    let code = post(&mut srv, data, |res| match res.which().unwrap() {
+        response::InvalidRequest(error) => error.unwrap().get_code().unwrap(),
+        _ => panic!("expected an invalid request response"),
+    });
+
+    assert_eq!(ErrorCode::RequestTooLarge, code);
+}
+
+#[test]
+#[ignore]
+fn logins_carry_the_request_id() {
+    dotenv().ok();
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let mut srv = serve(&database_url);
+    let username = format!("login{}", &Uuid::new_v4().simple().to_string()[..12]);
+
+    let data = encode(|mut req| {
+        req.set_request_id(7);
+        let mut registration = req.init_registration();
+        registration.set_username(&username);
+        registration.set_password(PASSWORD);
+    });
+    let request_id = post(&mut srv, data, |res| {
+        match res.which().unwrap() {
+            response::Login(login) => match login.which().unwrap() {
+                response::login::Success(_) => (),
+                _ => panic!("registration should succeed"),
+            },
+            _ => panic!("expected a login response"),
+        }
+        res.get_request_id()
+    });
+    assert_eq!(7, request_id);
+
+    let data = encode(|mut req| {
+        req.set_request_id(8);
+        let mut credentials = req.init_login().init_credentials();
+        credentials.set_username(&username);
+        credentials.set_password(PASSWORD);
+    });
+    let (request_id, logged_in) = post(&mut srv, data, |res| {
+        let logged_in = match res.which().unwrap() {
+            response::Login(login) => match login.which().unwrap() {
+                response::login::Success(success) => {
+                    success.get_user().unwrap().get_username().unwrap().to_string()
+                }
+                _ => panic!("login should succeed"),
+            },
+            _ => panic!("expected a login response"),
+        };
+        (res.get_request_id(), logged_in)
+    });
+    assert_eq!(8, request_id);
+    assert_eq!(username, logged_in);
+}
@@ -0,0 +1,136 @@
+//! Refresh tokens traded for new tokens with `AuthService::refresh`.
+//!
+//! Needs `DATABASE_URL` to point to a migrated database users may be added
+//! to, and `JWT_KEYS`. Run with `cargo test -p backend -- --ignored`.
+
+extern crate actix;
+extern crate backend;
+extern crate diesel;
+extern crate dotenv;
+extern crate failure;
+extern crate r2d2;
+extern crate uuid;
+
+use actix::{prelude::*, SystemRunner};
+use backend::{
+    auth::{AuthService, Authenticated},
+    database::{executor::DbExecutor, schema::sessions},
+    hasher::{Hasher, Scheme},
+    session::{ClientInfo, SessionCache},
+    throttle::{Limits, LoginThrottle},
+    token::Token,
+    validation::Policy,
+    ServerError,
+};
+use diesel::{prelude::*, r2d2::ConnectionManager};
+use dotenv::dotenv;
+use failure::Error;
+use r2d2::Pool;
+use std::{
+    env,
+    time::{Duration, SystemTime},
+};
+use uuid::Uuid;
+
+const PASSWORD: &str = "refresh Passw0rd";
+
+fn database_url() -> String {
+    dotenv().ok();
+    env::var("DATABASE_URL").expect("DATABASE_URL must be set")
+}
+
+fn start() -> (SystemRunner, AuthService) {
+    let sys = System::new("refresh");
+
+    let pool = Pool::builder()
+        .max_size(1)
+        .build(ConnectionManager::<PgConnection>::new(database_url()))
+        .expect("Failed to create pool");
+    let db = SyncArbiter::start(1, move || DbExecutor(pool.clone()));
+    // The cheapest hashes, the sessions are what is tested
+    let scheme = Scheme::Bcrypt { cost: 4 };
+    let dummy_hash = scheme.hash("dummy password").unwrap();
+    let hasher = SyncArbiter::start(1, move || Hasher::new(scheme, dummy_hash.clone()));
+    let auth = AuthService::new(
+        db,
+        hasher,
+        SessionCache::new(Duration::from_secs(30)),
+        Policy::default(),
+        LoginThrottle::new(Limits::default()),
+    );
+
+    (sys, auth)
+}
+
+fn register(sys: &mut SystemRunner, auth: &AuthService) -> Authenticated {
+    let username = format!("refresh{}", &Uuid::new_v4().simple().to_string()[..12]);
+    sys.block_on(auth.register(username, PASSWORD.to_string(), ClientInfo::default()))
+        .unwrap()
+}
+
+fn refresh(
+    sys: &mut SystemRunner,
+    auth: &AuthService,
+    refresh_token: &str,
+) -> Result<Authenticated, Error> {
+    sys.block_on(auth.refresh(refresh_token.to_string(), ClientInfo::default()))
+}
+
+fn assert_rejected(result: Result<Authenticated, Error>) {
+    match result.map_err(|e| e.downcast::<ServerError>()) {
+        Err(Ok(ServerError::VerifyToken)) => (),
+        Err(Ok(e)) => panic!("unexpected error: {}", e),
+        Err(Err(e)) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("the refresh token should be rejected"),
+    }
+}
+
+fn session_id(access: &str) -> String {
+    Token::verify(access).unwrap().session_id().to_string()
+}
+
+#[test]
+#[ignore]
+fn refreshing_rotates_both_tokens() {
+    let (mut sys, auth) = start();
+    let registered = register(&mut sys, &auth);
+
+    let refreshed = refresh(&mut sys, &auth, &registered.tokens.refresh).unwrap();
+
+    assert_eq!(registered.user.id, refreshed.user.id);
+    assert_ne!(registered.tokens.refresh, refreshed.tokens.refresh);
+    // Still the same session
+    assert_eq!(
+        session_id(&registered.tokens.access),
+        session_id(&refreshed.tokens.access)
+    );
+    assert!(sys.block_on(auth.verify(&refreshed.tokens.access)).is_ok());
+
+    // The new refresh token can be traded in turn
+    assert!(refresh(&mut sys, &auth, &refreshed.tokens.refresh).is_ok());
+}
+
+#[test]
+#[ignore]
+fn used_refresh_tokens_are_rejected() {
+    let (mut sys, auth) = start();
+    let registered = register(&mut sys, &auth);
+    refresh(&mut sys, &auth, &registered.tokens.refresh).unwrap();
+
+    assert_rejected(refresh(&mut sys, &auth, &registered.tokens.refresh));
+}
+
+#[test]
+#[ignore]
+fn expired_refresh_tokens_are_rejected() {
+    let (mut sys, auth) = start();
+    let registered = register(&mut sys, &auth);
+
+    let conn = PgConnection::establish(&database_url()).expect("Failed to connect");
+    diesel::update(sessions::table.find(session_id(&registered.tokens.access)))
+        .set(sessions::expires_at.eq(SystemTime::now() - Duration::from_secs(1)))
+        .execute(&conn)
+        .unwrap();
+
+    assert_rejected(refresh(&mut sys, &auth, &registered.tokens.refresh));
+}
@@ -0,0 +1,136 @@
+//! Sessions that are revoked while the `SessionCache` still holds them.
+//!
+//! The cache trusts a session for an hour here, so a token only gets rejected
+//! if revoking its session invalidated the cache entry. Needs `DATABASE_URL`
+//! to point to a migrated database users may be added to, and `JWT_KEYS`.
+//! Run with `cargo test -p backend -- --ignored`.
+
+extern crate actix;
+extern crate backend;
+extern crate diesel;
+extern crate dotenv;
+extern crate failure;
+extern crate r2d2;
+extern crate uuid;
+
+use actix::{prelude::*, SystemRunner};
+use backend::{
+    auth::{AuthService, Authenticated},
+    database::executor::{DbExecutor, RevokeSession},
+    hasher::{Hasher, Scheme},
+    session::{ClientInfo, SessionCache},
+    throttle::{Limits, LoginThrottle},
+    token::Token,
+    validation::Policy,
+    ServerError,
+};
+use diesel::{prelude::*, r2d2::ConnectionManager};
+use dotenv::dotenv;
+use failure::Error;
+use r2d2::Pool;
+use std::{env, time::Duration};
+use uuid::Uuid;
+
+const PASSWORD: &str = "session Passw0rd";
+
+fn start() -> (SystemRunner, Addr<DbExecutor>, AuthService) {
+    dotenv().ok();
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let sys = System::new("session_cache");
+
+    let pool = Pool::builder()
+        .max_size(1)
+        .build(ConnectionManager::<PgConnection>::new(database_url))
+        .expect("Failed to create pool");
+    let db = SyncArbiter::start(1, move || DbExecutor(pool.clone()));
+    // The cheapest hashes, the sessions are what is tested
+    let scheme = Scheme::Bcrypt { cost: 4 };
+    let dummy_hash = scheme.hash("dummy password").unwrap();
+    let hasher = SyncArbiter::start(1, move || Hasher::new(scheme, dummy_hash.clone()));
+    let auth = AuthService::new(
+        db.clone(),
+        hasher,
+        SessionCache::new(Duration::from_secs(3600)),
+        Policy::default(),
+        LoginThrottle::new(Limits::default()),
+    );
+
+    (sys, db, auth)
+}
+
+/// Register a new user and start a second session for them
+fn two_sessions(sys: &mut SystemRunner, auth: &AuthService) -> (Authenticated, Authenticated) {
+    let username = format!("session{}", &Uuid::new_v4().simple().to_string()[..12]);
+    let first = sys
+        .block_on(auth.register(username.clone(), PASSWORD.to_string(), ClientInfo::default()))
+        .unwrap();
+    let second = sys
+        .block_on(auth.login(username, PASSWORD.to_string(), ClientInfo::default()))
+        .unwrap();
+    (first, second)
+}
+
+/// Verify an access token, caching its session
+fn verify(sys: &mut SystemRunner, auth: &AuthService, access: &str) -> Result<Token, Error> {
+    sys.block_on(auth.verify(access))
+}
+
+fn assert_invalid_session(result: Result<Token, Error>) {
+    match result.map_err(|e| e.downcast::<ServerError>()) {
+        Err(Ok(ServerError::InvalidSession)) => (),
+        Err(Ok(e)) => panic!("unexpected error: {}", e),
+        Err(Err(e)) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("the session should be rejected"),
+    }
+}
+
+#[test]
+#[ignore]
+fn revoked_sessions_are_rejected_while_cached() {
+    let (mut sys, db, auth) = start();
+    let (first, second) = two_sessions(&mut sys, &auth);
+    let token = verify(&mut sys, &auth, &second.tokens.access).unwrap();
+
+    // Revoked from the first session, like the websocket does
+    sys.block_on(db.send(RevokeSession {
+        user_id: first.user.id,
+        session_id: token.session_id().to_string(),
+    })).unwrap()
+    .unwrap();
+    auth.forget_session(token.session_id());
+
+    assert_invalid_session(verify(&mut sys, &auth, &second.tokens.access));
+    assert!(verify(&mut sys, &auth, &first.tokens.access).is_ok());
+}
+
+#[test]
+#[ignore]
+fn logging_out_invalidates_the_cached_session() {
+    let (mut sys, _db, auth) = start();
+    let (first, second) = two_sessions(&mut sys, &auth);
+    verify(&mut sys, &auth, &first.tokens.access).unwrap();
+
+    sys.block_on(auth.logout(&first.tokens.access)).unwrap();
+
+    assert_invalid_session(verify(&mut sys, &auth, &first.tokens.access));
+    assert!(verify(&mut sys, &auth, &second.tokens.access).is_ok());
+}
+
+#[test]
+#[ignore]
+fn changing_the_password_invalidates_other_cached_sessions() {
+    let (mut sys, _db, auth) = start();
+    let (first, second) = two_sessions(&mut sys, &auth);
+    verify(&mut sys, &auth, &second.tokens.access).unwrap();
+
+    sys.block_on(auth.change_password(
+        &first.tokens.access,
+        PASSWORD.to_string(),
+        "changed Passw0rd".to_string(),
+        ClientInfo::default(),
+    )).unwrap();
+
+    assert_invalid_session(verify(&mut sys, &auth, &second.tokens.access));
+    // The session the password was changed from is kept
+    assert!(verify(&mut sys, &auth, &first.tokens.access).is_ok());
+}
@@ -0,0 +1,140 @@
+//! Votes cast on posts.
+//!
+//! Needs `DATABASE_URL` to point to a migrated database, everything added to
+//! it is rolled back. Run with `cargo test -p backend -- --ignored`.
+
+extern crate backend;
+extern crate diesel;
+extern crate dotenv;
+extern crate failure;
+
+use backend::{
+    chatserver::GENERAL_ROOM,
+    database::{
+        executor::{user_vote, UserVote},
+        models::{NewPost, NewUser, Post, User},
+        schema::{posts, users, votes},
+    },
+    ServerError,
+};
+use diesel::prelude::*;
+use dotenv::dotenv;
+use failure::Error;
+use std::env;
+
+/// A connection whose changes are rolled back, with a post and a user who can
+/// vote on it
+fn setup() -> (PgConnection, Post, User) {
+    dotenv().ok();
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    let conn = PgConnection::establish(&database_url).expect("Failed to connect");
+    conn.begin_test_transaction().unwrap();
+
+    let user = |name: &str| {
+        diesel::insert_into(users::table)
+            .values(&NewUser {
+                username: name.to_string(),
+                password: String::new(),
+                username_key: name.to_string(),
+            }).get_result::<User>(&conn)
+            .unwrap()
+    };
+    let author = user("user_vote_author");
+    let voter = user("user_vote_voter");
+    let post = diesel::insert_into(posts::table)
+        .values(&NewPost {
+            content: "vote on me".to_string(),
+            user_id: author.id,
+            room_id: GENERAL_ROOM,
+        }).get_result::<Post>(&conn)
+        .unwrap();
+
+    (conn, post, voter)
+}
+
+fn vote(conn: &PgConnection, post: &Post, user: &User, up_or_down: i16) -> Result<Post, Error> {
+    user_vote(
+        conn,
+        &UserVote {
+            post_id: post.id,
+            user_id: user.id,
+            up_or_down,
+        },
+    )
+}
+
+fn server_error(result: Result<Post, Error>) -> ServerError {
+    result
+        .expect_err("the vote should be rejected")
+        .downcast::<ServerError>()
+        .expect("expected a ServerError")
+}
+
+#[test]
+#[ignore]
+fn votes_on_missing_posts_are_rejected() {
+    let (conn, post, voter) = setup();
+    let missing = Post { id: -1, ..post };
+
+    match server_error(vote(&conn, &missing, &voter, 1)) {
+        ServerError::PostNotFound => (),
+        e => panic!("unexpected error: {}", e),
+    }
+}
+
+#[test]
+#[ignore]
+fn votes_on_closed_posts_are_rejected() {
+    let (conn, post, voter) = setup();
+    diesel::update(posts::table.find(post.id))
+        .set(posts::valid.eq(false))
+        .execute(&conn)
+        .unwrap();
+
+    match server_error(vote(&conn, &post, &voter, 1)) {
+        ServerError::PostClosed => (),
+        e => panic!("unexpected error: {}", e),
+    }
+}
+
+#[test]
+#[ignore]
+fn votes_on_own_posts_are_rejected() {
+    let (conn, post, _) = setup();
+    let author = users::table
+        .find(post.user_id)
+        .first::<User>(&conn)
+        .unwrap();
+
+    match server_error(vote(&conn, &post, &author, 1)) {
+        ServerError::SelfVote => (),
+        e => panic!("unexpected error: {}", e),
+    }
+}
+
+#[test]
+#[ignore]
+fn withdrawn_votes_are_deleted() {
+    let (conn, post, voter) = setup();
+    let karma = |conn: &PgConnection| {
+        users::table
+            .find(post.user_id)
+            .select(users::karma)
+            .first::<i32>(conn)
+            .unwrap()
+    };
+    let karma_before = karma(&conn);
+
+    let voted = vote(&conn, &post, &voter, 1).unwrap();
+    assert_eq!((1, 0), (voted.up_count, voted.down_count));
+
+    let withdrawn = vote(&conn, &post, &voter, 0).unwrap();
+    assert_eq!((0, 0), (withdrawn.up_count, withdrawn.down_count));
+    let left = votes::table
+        .find((voter.id, post.id))
+        .count()
+        .get_result::<i64>(&conn)
+        .unwrap();
+    assert_eq!(0, left);
+    assert_eq!(karma_before, karma(&conn));
+}
@@ -13,9 +13,16 @@ import { throws } from 'assert';
 import Login from './components/login';
 import Feed from './components/feed';
 
-import { ProtocolInterface, WsMessage, Vote } from '../../build/frontend';
+import {
+  ProtocolInterface, WsMessage, Vote, PostOrder,
+} from '../../build/frontend';
 
 const SESSION_TOKEN: string = 'SessionToken';
+const REFRESH_TOKEN: string = 'RefreshToken';
+// Access tokens live for 15 minutes, renew them well before that
+const REFRESH_INTERVAL: number = 10 * 60 * 1000;
+// Every session is in the general room, the UI has no others yet
+const GENERAL_ROOM: number = 0;
 
 const root = document.getElementById('root');
 
@@ -54,22 +61,42 @@ class App extends React.Component<{protocolService: ProtocolInterface}, State> {
   }
 
   componentDidMount() {
-    const token = Cookies.get(SESSION_TOKEN);
+    if (Cookies.get(REFRESH_TOKEN)) {
+      this.refresh_session();
+    } else {
+      Cookies.remove(SESSION_TOKEN);
+      this.setState({ is_loading: false });
+    }
 
-    if (token) {
-      const token_data = this.props.protocolService.write_login_token(token);
+    this.refresh_timer = setInterval(() => {
+      if (this.state.is_authenticated) {
+        this.refresh_session();
+      }
+    }, REFRESH_INTERVAL);
+  }
+
+  componentWillUnmount() {
+    clearInterval(this.refresh_timer);
+  }
+
+  refresh_session = () => {
+    const refresh_token = Cookies.get(REFRESH_TOKEN);
+    if (refresh_token) {
+      const data = this.props.protocolService.write_refresh_session(refresh_token);
       fetch('/login', {
         method: 'POST',
-        body: token_data,
+        body: data,
       }).then(response => response.arrayBuffer()).then((buffer) => {
         this.handle_message({ data: buffer });
       });
-    } else {
-      Cookies.remove(SESSION_TOKEN);
-      this.setState({ is_loading: false });
     }
   }
 
+  clear_session = () => {
+    Cookies.remove(SESSION_TOKEN);
+    Cookies.remove(REFRESH_TOKEN);
+  }
+
   connect_to_ws = () => {
     if (this.state.ws != null) {
       this.state.ws.close(1000, '');
@@ -104,13 +131,17 @@ class App extends React.Component<{protocolService: ProtocolInterface}, State> {
 
         if (login_res) {
           Cookies.set(SESSION_TOKEN, login_res.token);
+          if (login_res.refresh_token) {
+            Cookies.set(REFRESH_TOKEN, login_res.refresh_token);
+          }
           this.setState({ user: login_res.user, is_authenticated: true });
           this.connect_to_ws();
         } else if (!this.state.is_authenticated) {
           this.setState({ is_loading: false });
-          Cookies.remove(SESSION_TOKEN);
+          this.clear_session();
+          const error = protocolService.read_error(data);
           UIkit.notification(
-            'An error occured when attempting to login',
+            error ? error.message : 'An error occured when attempting to login',
           );
         }
         break; }
@@ -121,15 +152,33 @@ class App extends React.Component<{protocolService: ProtocolInterface}, State> {
           );
         } else {
           this.state.ws.close(1000, '');
+          this.clear_session();
           this.setState({ is_authenticated: false, ws: null, posts: [] });
         }
         break;
+      case WsMessage.RefreshSession: {
+        const refresh_res = protocolService.read_refresh_session(data);
+
+        if (refresh_res) {
+          Cookies.set(SESSION_TOKEN, refresh_res.token);
+          Cookies.set(REFRESH_TOKEN, refresh_res.refresh_token);
+          if (this.state.is_authenticated) {
+            this.setState({ user: refresh_res.user });
+          } else {
+            this.setState({ user: refresh_res.user, is_authenticated: true });
+            this.connect_to_ws();
+          }
+        } else if (!this.state.is_authenticated) {
+          this.setState({ is_loading: false });
+          this.clear_session();
+        }
+        break; }
       case WsMessage.FetchPosts: {
         const fetch_res = protocolService.read_fetch_posts(data);
 
         if (fetch_res) {
-          Cookies.set(SESSION_TOKEN, fetch_res.token);
-          this.setState({ posts: fetch_res.posts });
+          // Pages come newest first, the feed lists the newest post last
+          this.setState({ posts: fetch_res.posts.reverse() });
         } else {
           UIkit.notification(
             'An error occured when attempting to fetching posts',
@@ -141,7 +190,6 @@ class App extends React.Component<{protocolService: ProtocolInterface}, State> {
         const post_res = protocolService.read_create_post(data);
 
         if (post_res) {
-          Cookies.set(SESSION_TOKEN, post_res.token);
           this.setState(prevState => ({
             posts: [...prevState.posts, post_res.post],
           }));
@@ -153,12 +201,11 @@ class App extends React.Component<{protocolService: ProtocolInterface}, State> {
         }
         break; }
       case WsMessage.UserVote: {
-        const vote_res = protocolService.read_user_vote(data);
-
-        if (vote_res) {
-          Cookies.set(SESSION_TOKEN, vote_res);
-        } else {
-          UIkit.notification('An error occured when attempting to vote on a post');
+        if (!protocolService.read_user_vote(data)) {
+          const error = protocolService.read_error(data);
+          UIkit.notification(
+            error ? error.message : 'An error occured when attempting to vote on a post',
+          );
         }
         break; }
       case WsMessage.InvalidPosts: {
@@ -186,6 +233,18 @@ class App extends React.Component<{protocolService: ProtocolInterface}, State> {
           }));
         }
         break; }
+      case WsMessage.VoteTally: {
+        const tally = protocolService.read_vote_tally(data);
+        if (tally) {
+          this.setState(prevState => ({
+            posts: prevState.posts.map(p => (p.id === tally.postId
+              ? {
+                ...p, upCount: tally.upCount, downCount: tally.downCount, talliesHidden: false,
+              }
+              : p)),
+          }));
+        }
+        break; }
       case WsMessage.UpdateUsers: {
         const updated_users = protocolService.read_update_users(data);
         if (updated_users) {
@@ -218,6 +277,10 @@ class App extends React.Component<{protocolService: ProtocolInterface}, State> {
         }
         this.setState({ is_loading: false });
         break;
+      case WsMessage.InvalidRequest: {
+        const error = protocolService.read_error(data);
+        UIkit.notification(error ? error.message : 'Invalid request', 'warning');
+        break; }
       case WsMessage.Error:
       default:
     }
@@ -262,7 +325,7 @@ class App extends React.Component<{protocolService: ProtocolInterface}, State> {
   create_post_request = (message: string) => {
     const token = Cookies.get(SESSION_TOKEN);
     if (token) {
-      const data = this.props.protocolService.write_create_post(token, message);
+      const data = this.props.protocolService.write_create_post(token, message, GENERAL_ROOM);
       if (data) {
         this.state.ws.send(data);
       }
@@ -282,7 +345,10 @@ class App extends React.Component<{protocolService: ProtocolInterface}, State> {
   fetch_posts = () => {
     const token = Cookies.get(SESSION_TOKEN);
     if (token) {
-      const token_data = this.props.protocolService.write_fetch_posts(token);
+      // The latest page of posts, as many as the server sends by default
+      const token_data = this.props.protocolService.write_fetch_posts(
+        token, GENERAL_ROOM, '', 0, PostOrder.NewestFirst,
+      );
       this.state.ws.send(token_data);
     }
   }
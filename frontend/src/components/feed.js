@@ -48,6 +48,9 @@ export default class Feed extends React.Component<Props, State> {
           content={p.content}
           isMine={this.props.user.id === p.userId}
           vote={p.vote}
+          upCount={p.upCount}
+          downCount={p.downCount}
+          talliesHidden={p.talliesHidden}
           onVote={this.props.voteRequest}
         />
       </div>
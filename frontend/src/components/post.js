@@ -11,6 +11,9 @@ type Props = {
     content: string,
     vote: string,
     id: number,
+    upCount: number,
+    downCount: number,
+    talliesHidden: boolean,
     onVote: (n: number, vote: Vote) => void,
     isMine: boolean,
 }
@@ -35,10 +38,10 @@ export default class UserPost extends React.Component<Props, State> {
   }
 
     handle_vote = (vote: Vote) => {
-      if (this.state.vote !== vote) {
-        this.props.onVote(this.props.id, vote);
-        this.setState({ vote });
-      }
+      // Voting the same way again withdraws the vote
+      const next = this.state.vote === vote ? Vote.None : vote;
+      this.props.onVote(this.props.id, next);
+      this.setState({ vote: next });
     }
 
     handle_downvote = () => {
@@ -77,12 +80,14 @@ export default class UserPost extends React.Component<Props, State> {
               style={this.get_style_up()}
               role="button"
             />
+            {this.props.talliesHidden ? null : (<small>{this.props.upCount}</small>)}
             <span
               uk-icon="icon: arrow-down"
               onClick={this.handle_downvote}
               style={this.get_style_down()}
               role="button"
             />
+            {this.props.talliesHidden ? null : (<small>{this.props.downCount}</small>)}
           </div>
           <span className="uk-border-rounded" style={messageStyle}>{this.props.content}</span>
         </li>
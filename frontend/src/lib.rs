@@ -14,7 +14,9 @@ use failure::Error;
 
 pub use wakkave::protocol_capnp;
 
-use protocol_capnp::{post as Post_P, Vote as Vote_P};
+use protocol_capnp::{
+    post as Post_P, ErrorCode as ErrorCode_P, PostOrder as PostOrder_P, Vote as Vote_P,
+};
 
 pub mod protocol;
 use protocol::ProtocolService;
@@ -50,6 +52,25 @@ impl ProtocolInterface {
         }
     }
 
+    /// The id of the request a response answers, 0 for updates
+    pub fn request_id(&self, bytes: &[u8]) -> u64 {
+        ProtocolService::request_id(bytes).unwrap_or(0)
+    }
+
+    /// The id of the last request written, to match it with its response
+    pub fn last_request_id(&self) -> u64 {
+        self.protocol_builder.last_request_id()
+    }
+
+    pub fn read_error(&self, bytes: &[u8]) -> JsValue {
+        // returns an instance of ResponseError
+        if let Ok(Some(error)) = self.protocol_builder.read_response_error(bytes) {
+            JsValue::from_serde(&ResponseError::from(error)).unwrap()
+        } else {
+            JsValue::null()
+        }
+    }
+
     pub fn read_login(&self, bytes: &[u8]) -> JsValue {
         if let Ok(res) = self.protocol_builder.read_response_login(bytes) {
             JsValue::from_serde(&res.unwrap()).unwrap()
@@ -84,11 +105,11 @@ impl ProtocolInterface {
         }
     }
 
-    pub fn read_user_vote(&self, bytes: &[u8]) -> Option<String> {
-        if let Ok(res) = self.protocol_builder.read_request_user_vote(bytes) {
-            res
+    pub fn read_user_vote(&self, bytes: &[u8]) -> bool {
+        if let Ok(Some(())) = self.protocol_builder.read_request_user_vote(bytes) {
+            true
         } else {
-            None
+            false
         }
     }
 
@@ -100,6 +121,15 @@ impl ProtocolInterface {
         }
     }
 
+    pub fn read_vote_tally(&self, bytes: &[u8]) -> JsValue {
+        // returns an instance of VoteTally
+        if let Ok(Some(tally)) = self.protocol_builder.read_update_vote_tally(bytes) {
+            JsValue::from_serde(&tally).unwrap()
+        } else {
+            JsValue::null()
+        }
+    }
+
     pub fn read_invalid_posts(&self, bytes: &[u8]) -> Option<Box<[i32]>> {
         if let Ok(res) = self.protocol_builder.read_update_invalid(bytes) {
             res.map(|v| v.into_boxed_slice())
@@ -116,6 +146,74 @@ impl ProtocolInterface {
         }
     }
 
+    pub fn read_refresh_session(&self, bytes: &[u8]) -> JsValue {
+        // returns an instance of LoginResponse
+        if let Ok(res) = self.protocol_builder.read_response_refresh_session(bytes) {
+            JsValue::from_serde(&res.unwrap()).unwrap()
+        } else {
+            JsValue::null()
+        }
+    }
+
+    pub fn read_list_sessions(&self, bytes: &[u8]) -> JsValue {
+        // returns an array of SessionInfo
+        if let Ok(res) = self.protocol_builder.read_response_list_sessions(bytes) {
+            JsValue::from_serde(&res.unwrap()).unwrap()
+        } else {
+            JsValue::null()
+        }
+    }
+
+    pub fn read_revoke_session(&self, bytes: &[u8]) -> bool {
+        if let Ok(Some(())) = self.protocol_builder.read_response_revoke_session(bytes) {
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn read_change_password(&self, bytes: &[u8]) -> bool {
+        if let Ok(Some(())) = self.protocol_builder.read_response_change_password(bytes) {
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn read_delete_account(&self, bytes: &[u8]) -> bool {
+        if let Ok(Some(())) = self.protocol_builder.read_response_delete_account(bytes) {
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn read_join_room(&self, bytes: &[u8]) -> JsValue {
+        // returns an instance of Room
+        if let Ok(res) = self.protocol_builder.read_response_join_room(bytes) {
+            JsValue::from_serde(&res.unwrap()).unwrap()
+        } else {
+            JsValue::null()
+        }
+    }
+
+    pub fn read_leave_room(&self, bytes: &[u8]) -> bool {
+        if let Ok(Some(())) = self.protocol_builder.read_response_leave_room(bytes) {
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn read_list_rooms(&self, bytes: &[u8]) -> JsValue {
+        // returns an array of Room
+        if let Ok(res) = self.protocol_builder.read_response_list_rooms(bytes) {
+            JsValue::from_serde(&res.unwrap()).unwrap()
+        } else {
+            JsValue::null()
+        }
+    }
+
     pub fn read_connect_to_chat(&self, bytes: &[u8]) -> bool {
         if let Ok(Some(())) = self.protocol_builder.read_response_connect_to_chat(bytes) {
             true
@@ -151,6 +249,14 @@ impl ProtocolInterface {
         }
     }
 
+    pub fn write_logout_all(&mut self, token: &str) -> Option<Box<[u8]>> {
+        if let Ok(res) = self.protocol_builder.write_request_logout_all(token) {
+            Some(res.to_vec().into_boxed_slice())
+        } else {
+            None
+        }
+    }
+
     pub fn write_registration(&mut self, name: &str, password: &str) -> Option<Box<[u8]>> {
         if let Ok(res) = self
             .protocol_builder
@@ -162,18 +268,40 @@ impl ProtocolInterface {
         }
     }
 
-    pub fn write_fetch_posts(&mut self, token: &str) -> Option<Box<[u8]>> {
-        if let Ok(res) = self.protocol_builder.write_request_fetch_posts(token) {
+    /// Ask for a page of the posts of a room, the first page if `cursor` is
+    /// empty and as many posts as the server sends by default if `limit` is 0
+    pub fn write_fetch_posts(
+        &mut self,
+        token: &str,
+        room_id: i32,
+        cursor: &str,
+        limit: u16,
+        order: u32,
+    ) -> Option<Box<[u8]>> {
+        let order = match order {
+            0 => PostOrder::NewestFirst,
+            1 => PostOrder::OldestFirst,
+            _ => return None,
+        };
+        if let Ok(res) = self
+            .protocol_builder
+            .write_request_fetch_posts(token, room_id, cursor, limit, order)
+        {
             Some(res.to_vec().into_boxed_slice())
         } else {
             None
         }
     }
 
-    pub fn write_create_post(&mut self, token: &str, content: &str) -> Option<Box<[u8]>> {
+    pub fn write_create_post(
+        &mut self,
+        token: &str,
+        content: &str,
+        room_id: i32,
+    ) -> Option<Box<[u8]>> {
         if let Ok(res) = self
             .protocol_builder
-            .write_request_create_post(token, content)
+            .write_request_create_post(token, content, room_id)
         {
             Some(res.to_vec().into_boxed_slice())
         } else {
@@ -184,6 +312,7 @@ impl ProtocolInterface {
     pub fn write_user_vote(&mut self, token: &str, post_id: i32, vote: u32) -> Option<Box<[u8]>> {
         let vote = match vote {
             0 => Vote::Up,
+            1 => Vote::None,
             2 => Vote::Down,
             _ => return None,
         };
@@ -197,6 +326,30 @@ impl ProtocolInterface {
         }
     }
 
+    pub fn write_join_room(&mut self, token: &str, name: &str) -> Option<Box<[u8]>> {
+        if let Ok(res) = self.protocol_builder.write_request_join_room(token, name) {
+            Some(res.to_vec().into_boxed_slice())
+        } else {
+            None
+        }
+    }
+
+    pub fn write_leave_room(&mut self, token: &str, room_id: i32) -> Option<Box<[u8]>> {
+        if let Ok(res) = self.protocol_builder.write_request_leave_room(token, room_id) {
+            Some(res.to_vec().into_boxed_slice())
+        } else {
+            None
+        }
+    }
+
+    pub fn write_list_rooms(&mut self, token: &str) -> Option<Box<[u8]>> {
+        if let Ok(res) = self.protocol_builder.write_request_list_rooms(token) {
+            Some(res.to_vec().into_boxed_slice())
+        } else {
+            None
+        }
+    }
+
     pub fn write_connect_to_chat(&mut self, token: &str) -> Option<Box<[u8]>> {
         if let Ok(res) = self.protocol_builder.write_request_connect_to_chat(token) {
             Some(res.to_vec().into_boxed_slice())
@@ -204,17 +357,75 @@ impl ProtocolInterface {
             None
         }
     }
+
+    pub fn write_list_sessions(&mut self, token: &str) -> Option<Box<[u8]>> {
+        if let Ok(res) = self.protocol_builder.write_request_list_sessions(token) {
+            Some(res.to_vec().into_boxed_slice())
+        } else {
+            None
+        }
+    }
+
+    pub fn write_revoke_session(&mut self, token: &str, session_id: &str) -> Option<Box<[u8]>> {
+        if let Ok(res) = self
+            .protocol_builder
+            .write_request_revoke_session(token, session_id)
+        {
+            Some(res.to_vec().into_boxed_slice())
+        } else {
+            None
+        }
+    }
+
+    pub fn write_change_password(
+        &mut self,
+        token: &str,
+        current_password: &str,
+        new_password: &str,
+    ) -> Option<Box<[u8]>> {
+        if let Ok(res) = self.protocol_builder.write_request_change_password(
+            token,
+            current_password,
+            new_password,
+        ) {
+            Some(res.to_vec().into_boxed_slice())
+        } else {
+            None
+        }
+    }
+
+    pub fn write_delete_account(&mut self, token: &str, password: &str) -> Option<Box<[u8]>> {
+        if let Ok(res) = self
+            .protocol_builder
+            .write_request_delete_account(token, password)
+        {
+            Some(res.to_vec().into_boxed_slice())
+        } else {
+            None
+        }
+    }
+
+    pub fn write_refresh_session(&mut self, refresh_token: &str) -> Option<Box<[u8]>> {
+        if let Ok(res) = self
+            .protocol_builder
+            .write_request_refresh_session(refresh_token)
+        {
+            Some(res.to_vec().into_boxed_slice())
+        } else {
+            None
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FetchedPosts {
-    token: String,
     posts: Vec<Post>,
+    /// Where the next page starts, if there is one
+    next_cursor: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreatedPost {
-    token: String,
     post: Post,
 }
 
@@ -226,9 +437,21 @@ pub struct UsersToUpdate {
 #[derive(Serialize, Deserialize)]
 pub struct LoginResponse {
     token: String,
+    refresh_token: String,
     user: User,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct SessionInfo {
+    id: String,
+    createdAt: i64,
+    expiresAt: i64,
+    lastSeenAt: i64,
+    userAgent: String,
+    ipAddress: String,
+    current: bool,
+}
+
 #[wasm_bindgen]
 #[derive(Debug, PartialEq)]
 pub enum WsMessage {
@@ -242,6 +465,16 @@ pub enum WsMessage {
     UpdateUsers,
     Error,
     ConnectToChat,
+    RefreshSession,
+    ListSessions,
+    RevokeSession,
+    ChangePassword,
+    DeleteAccount,
+    InvalidRequest,
+    JoinRoom,
+    LeaveRoom,
+    ListRooms,
+    VoteTally,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -259,6 +492,24 @@ pub struct Post {
     valid: bool,
     vote: Vote,
     userId: i32,
+    roomId: i32,
+    upCount: i32,
+    downCount: i32,
+    /// The counts are 0 until voting on the post closes
+    talliesHidden: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VoteTally {
+    postId: i32,
+    upCount: i32,
+    downCount: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Room {
+    id: i32,
+    name: String,
 }
 
 #[wasm_bindgen]
@@ -289,6 +540,97 @@ impl From<Vote_P> for Vote {
     }
 }
 
+#[wasm_bindgen]
+#[derive(Debug, Serialize, Deserialize)]
+pub enum PostOrder {
+    NewestFirst,
+    OldestFirst,
+}
+
+impl Into<PostOrder_P> for PostOrder {
+    fn into(self) -> PostOrder_P {
+        match self {
+            PostOrder::NewestFirst => PostOrder_P::NewestFirst,
+            PostOrder::OldestFirst => PostOrder_P::OldestFirst,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ResponseError {
+    code: ErrorCode,
+    message: String,
+    field: Option<String>,
+}
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    Unknown,
+    MalformedRequest,
+    CreateToken,
+    VerifyToken,
+    InvalidSession,
+    InsertToken,
+    InsertPost,
+    UpdateToken,
+    RemoveToken,
+    FindSessions,
+    JoinChat,
+    CreateUser,
+    UsernameTaken,
+    UsernameInvalid,
+    PasswordTooWeak,
+    FindUser,
+    InsertVote,
+    IncorrectPassword,
+    InvalidVote,
+    LoginLocked,
+    InvalidCredentials,
+    RequestTooLarge,
+    RoomNameInvalid,
+    InvalidCursor,
+    PostClosed,
+    SelfVote,
+    PostNotFound,
+    RoomNotFound,
+}
+
+impl From<ErrorCode_P> for ErrorCode {
+    fn from(code: ErrorCode_P) -> Self {
+        match code {
+            ErrorCode_P::Unknown => ErrorCode::Unknown,
+            ErrorCode_P::MalformedRequest => ErrorCode::MalformedRequest,
+            ErrorCode_P::CreateToken => ErrorCode::CreateToken,
+            ErrorCode_P::VerifyToken => ErrorCode::VerifyToken,
+            ErrorCode_P::InvalidSession => ErrorCode::InvalidSession,
+            ErrorCode_P::InsertToken => ErrorCode::InsertToken,
+            ErrorCode_P::InsertPost => ErrorCode::InsertPost,
+            ErrorCode_P::UpdateToken => ErrorCode::UpdateToken,
+            ErrorCode_P::RemoveToken => ErrorCode::RemoveToken,
+            ErrorCode_P::FindSessions => ErrorCode::FindSessions,
+            ErrorCode_P::JoinChat => ErrorCode::JoinChat,
+            ErrorCode_P::CreateUser => ErrorCode::CreateUser,
+            ErrorCode_P::UsernameTaken => ErrorCode::UsernameTaken,
+            ErrorCode_P::UsernameInvalid => ErrorCode::UsernameInvalid,
+            ErrorCode_P::PasswordTooWeak => ErrorCode::PasswordTooWeak,
+            ErrorCode_P::FindUser => ErrorCode::FindUser,
+            ErrorCode_P::InsertVote => ErrorCode::InsertVote,
+            ErrorCode_P::IncorrectPassword => ErrorCode::IncorrectPassword,
+            ErrorCode_P::InvalidVote => ErrorCode::InvalidVote,
+            ErrorCode_P::LoginLocked => ErrorCode::LoginLocked,
+            ErrorCode_P::InvalidCredentials => ErrorCode::InvalidCredentials,
+            ErrorCode_P::RequestTooLarge => ErrorCode::RequestTooLarge,
+            ErrorCode_P::RoomNameInvalid => ErrorCode::RoomNameInvalid,
+            ErrorCode_P::InvalidCursor => ErrorCode::InvalidCursor,
+            ErrorCode_P::PostClosed => ErrorCode::PostClosed,
+            ErrorCode_P::SelfVote => ErrorCode::SelfVote,
+            ErrorCode_P::PostNotFound => ErrorCode::PostNotFound,
+            ErrorCode_P::RoomNotFound => ErrorCode::RoomNotFound,
+        }
+    }
+}
+
 #[derive(Debug, Fail)]
 #[fail(display = "Invalid Request")]
 pub struct InvalidRequest;
@@ -300,7 +642,9 @@ mod tests {
         message::{Builder, HeapAllocator, ReaderOptions},
         serialize_packed,
     };
-    use protocol_capnp::{post as Post_P, request, response, update, Vote as Vote_P};
+    use protocol_capnp::{
+        post as Post_P, request, response, update, ErrorCode as ErrorCode_P, Vote as Vote_P,
+    };
     use {CreatedPost, FetchedPosts, LoginResponse, Post, User, UsersToUpdate, Vote, WsMessage};
 
     use std::time::SystemTime;
@@ -361,13 +705,11 @@ mod tests {
         let mut b = Builder::new_default();
         let mut data = Vec::new();
         {
-            let mut success = b
+            let mut p = b
                 .init_root::<response::Builder>()
                 .init_create_post()
-                .init_success();
-
-            success.set_token("new_token");
-            let mut p = success.init_post();
+                .init_success()
+                .init_post();
             p.set_id(2);
             p.set_content("&post.content");
             p.set_valid(true);
@@ -383,4 +725,70 @@ mod tests {
             protocol_service.response_type(boxed_data)
         );
     }
+
+    #[test]
+    fn request_id() {
+        let mut protocol_service = ProtocolInterface::new();
+        assert_eq!(0, protocol_service.last_request_id());
+
+        let first = protocol_service
+            .write_fetch_posts("token", 0, "", 0, 0)
+            .unwrap();
+        let first_id = protocol_service.last_request_id();
+        protocol_service
+            .write_fetch_posts("token", 0, "", 0, 0)
+            .unwrap();
+        assert_ne!(first_id, protocol_service.last_request_id());
+
+        let reader =
+            serialize_packed::read_message(&mut first.as_ref(), ReaderOptions::new()).unwrap();
+        let request = reader.get_root::<request::Reader>().unwrap();
+        assert_eq!(first_id, request.get_request_id());
+
+        let mut b = Builder::new_default();
+        let mut data = Vec::new();
+        {
+            let mut res = b.init_root::<response::Builder>();
+            res.set_request_id(first_id);
+            res.init_fetch_posts().init_success().init_posts(0);
+        }
+
+        let _ = serialize_packed::write_message(&mut data, &b);
+
+        assert_eq!(first_id, protocol_service.request_id(&data));
+    }
+
+    #[test]
+    fn error_code() {
+        let protocol_service = ProtocolService::new();
+        let mut b = Builder::new_default();
+        let mut data = Vec::new();
+        {
+            let mut error = b
+                .init_root::<response::Builder>()
+                .init_login()
+                .init_error();
+            error.set_code(ErrorCode_P::UsernameTaken);
+            error.set_message("Username is already taken");
+            error.set_field("username");
+        }
+
+        let _ = serialize_packed::write_message(&mut data, &b);
+
+        let error = protocol_service
+            .read_response_login(&data)
+            .err()
+            .expect("login should have failed");
+        let error = error
+            .downcast_ref::<protocol::ProtocolError>()
+            .expect("should be an error response");
+        assert_eq!(ErrorCode::UsernameTaken, error.code());
+        assert_eq!(Some("username"), error.field());
+
+        let error = protocol_service
+            .read_response_error(&data)
+            .unwrap()
+            .expect("response should carry an error");
+        assert_eq!(ErrorCode::UsernameTaken, error.code());
+    }
 }
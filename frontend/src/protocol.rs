@@ -4,20 +4,72 @@ use capnp::{
     message::{Builder, HeapAllocator, ReaderOptions},
     serialize_packed,
 };
-use protocol_capnp::{post as Post_P, request, response, update, Vote as Vote_P};
+use protocol_capnp::{error, post as Post_P, request, response, update, Vote as Vote_P};
 
 use failure::Error;
-use {CreatedPost, FetchedPosts, LoginResponse, Post, User, UsersToUpdate, Vote, WsMessage};
+use {
+    CreatedPost, ErrorCode, FetchedPosts, LoginResponse, Post, PostOrder, ResponseError, Room,
+    SessionInfo, User, UsersToUpdate, Vote, VoteTally, WsMessage,
+};
 
 #[derive(Debug, Fail)]
 pub enum ProtocolError {
-    #[fail(display = "Error response: {}", description)]
-    Response { description: String },
+    #[fail(display = "Error response: {}", message)]
+    Response {
+        code: ErrorCode,
+        message: String,
+        field: Option<String>,
+    },
+}
+
+impl ProtocolError {
+    fn read(error: error::Reader) -> Result<Self, Error> {
+        let field = error.get_field()?;
+        Ok(ProtocolError::Response {
+            code: error.get_code()?.into(),
+            message: error.get_message()?.to_string(),
+            field: if field.is_empty() {
+                None
+            } else {
+                Some(field.to_string())
+            },
+        })
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        match *self {
+            ProtocolError::Response { code, .. } => code,
+        }
+    }
+
+    pub fn field(&self) -> Option<&str> {
+        match *self {
+            ProtocolError::Response { ref field, .. } => field.as_ref().map(String::as_str),
+        }
+    }
+}
+
+impl From<ProtocolError> for ResponseError {
+    fn from(e: ProtocolError) -> Self {
+        match e {
+            ProtocolError::Response {
+                code,
+                message,
+                field,
+            } => ResponseError {
+                code,
+                message,
+                field,
+            },
+        }
+    }
 }
 
 pub struct ProtocolService {
     data: Vec<u8>,
     builder: Builder<HeapAllocator>,
+    /// Id of the last request written, each request gets the next one
+    request_id: u64,
 }
 
 impl ProtocolService {
@@ -25,16 +77,35 @@ impl ProtocolService {
         ProtocolService {
             data: Vec::new(),
             builder: Builder::new_default(),
+            request_id: 0,
         }
     }
 
     fn write(&mut self) -> Result<&[u8], Error> {
         self.data.clear();
 
+        self.request_id += 1;
+        self.builder
+            .get_root::<request::Builder>()?
+            .set_request_id(self.request_id);
+
         serialize_packed::write_message(&mut self.data, &self.builder)?;
         Ok(&self.data)
     }
 
+    /// The id the response to the last request written will carry
+    pub fn last_request_id(&self) -> u64 {
+        self.request_id
+    }
+
+    /// The id of the request a response answers, 0 for updates
+    pub fn request_id(mut data: &[u8]) -> Result<u64, Error> {
+        let reader = serialize_packed::read_message(&mut data, ReaderOptions::new())?;
+        let response = reader.get_root::<response::Reader>()?;
+
+        Ok(response.get_request_id())
+    }
+
     pub fn which_message(mut data: &[u8]) -> Result<WsMessage, Error> {
         let reader = serialize_packed::read_message(&mut data, ReaderOptions::new())?;
         let response = reader.get_root::<response::Reader>()?;
@@ -47,10 +118,20 @@ impl ProtocolService {
             response::CreatePost(_) => WsMessage::CreatePost,
             response::UserVote(_) => WsMessage::UserVote,
             response::ConnectToChat(_) => WsMessage::ConnectToChat,
+            response::RefreshSession(_) => WsMessage::RefreshSession,
+            response::ListSessions(_) => WsMessage::ListSessions,
+            response::RevokeSession(_) => WsMessage::RevokeSession,
+            response::ChangePassword(_) => WsMessage::ChangePassword,
+            response::DeleteAccount(_) => WsMessage::DeleteAccount,
+            response::InvalidRequest(_) => WsMessage::InvalidRequest,
+            response::JoinRoom(_) => WsMessage::JoinRoom,
+            response::LeaveRoom(_) => WsMessage::LeaveRoom,
+            response::ListRooms(_) => WsMessage::ListRooms,
             response::Update(data) => match data?.which()? {
                 update::Invalid(_) => WsMessage::InvalidPosts,
                 update::Users(_) => WsMessage::UpdateUsers,
                 update::NewPost(_) => WsMessage::NewPost,
+                update::VoteTally(_) => WsMessage::VoteTally,
             },
         };
 
@@ -94,6 +175,16 @@ impl ProtocolService {
         self.write()
     }
 
+    pub fn write_request_logout_all(&mut self, token: &str) -> Result<&[u8], Error> {
+        {
+            self.builder
+                .init_root::<request::Builder>()
+                .set_logout_all(token);
+        }
+
+        self.write()
+    }
+
     pub fn write_request_registration(
         &mut self,
         name: &str,
@@ -110,11 +201,24 @@ impl ProtocolService {
         self.write()
     }
 
-    pub fn write_request_fetch_posts(&mut self, token: &str) -> Result<&[u8], Error> {
+    pub fn write_request_fetch_posts(
+        &mut self,
+        token: &str,
+        room_id: i32,
+        cursor: &str,
+        limit: u16,
+        order: PostOrder,
+    ) -> Result<&[u8], Error> {
         {
-            self.builder
+            let mut req = self
+                .builder
                 .init_root::<request::Builder>()
-                .set_fetch_posts(token);
+                .init_fetch_posts_page();
+            req.set_token(token);
+            req.set_room_id(room_id);
+            req.set_cursor(cursor);
+            req.set_limit(limit);
+            req.set_order(order.into());
         }
 
         self.write()
@@ -124,6 +228,7 @@ impl ProtocolService {
         &mut self,
         token: &str,
         content: &str,
+        room_id: i32,
     ) -> Result<&[u8], Error> {
         {
             let mut req = self
@@ -132,6 +237,7 @@ impl ProtocolService {
                 .init_create_post();
             req.set_token(token);
             req.set_content(content);
+            req.set_room_id(room_id);
         }
 
         self.write()
@@ -162,6 +268,115 @@ impl ProtocolService {
         self.write()
     }
 
+    pub fn write_request_refresh_session(&mut self, refresh_token: &str) -> Result<&[u8], Error> {
+        {
+            self.builder
+                .init_root::<request::Builder>()
+                .set_refresh_session(refresh_token);
+        }
+
+        self.write()
+    }
+
+    pub fn write_request_list_sessions(&mut self, token: &str) -> Result<&[u8], Error> {
+        {
+            self.builder
+                .init_root::<request::Builder>()
+                .set_list_sessions(token);
+        }
+
+        self.write()
+    }
+
+    pub fn write_request_join_room(&mut self, token: &str, name: &str) -> Result<&[u8], Error> {
+        {
+            let mut req = self
+                .builder
+                .init_root::<request::Builder>()
+                .init_join_room();
+            req.set_token(token);
+            req.set_name(name);
+        }
+
+        self.write()
+    }
+
+    pub fn write_request_leave_room(&mut self, token: &str, room_id: i32) -> Result<&[u8], Error> {
+        {
+            let mut req = self
+                .builder
+                .init_root::<request::Builder>()
+                .init_leave_room();
+            req.set_token(token);
+            req.set_room_id(room_id);
+        }
+
+        self.write()
+    }
+
+    pub fn write_request_list_rooms(&mut self, token: &str) -> Result<&[u8], Error> {
+        {
+            self.builder
+                .init_root::<request::Builder>()
+                .set_list_rooms(token);
+        }
+
+        self.write()
+    }
+
+    pub fn write_request_revoke_session(
+        &mut self,
+        token: &str,
+        session_id: &str,
+    ) -> Result<&[u8], Error> {
+        {
+            let mut req = self
+                .builder
+                .init_root::<request::Builder>()
+                .init_revoke_session();
+            req.set_token(token);
+            req.set_session_id(session_id);
+        }
+
+        self.write()
+    }
+
+    pub fn write_request_change_password(
+        &mut self,
+        token: &str,
+        current_password: &str,
+        new_password: &str,
+    ) -> Result<&[u8], Error> {
+        {
+            let mut req = self
+                .builder
+                .init_root::<request::Builder>()
+                .init_change_password();
+            req.set_token(token);
+            req.set_current_password(current_password);
+            req.set_new_password(new_password);
+        }
+
+        self.write()
+    }
+
+    pub fn write_request_delete_account(
+        &mut self,
+        token: &str,
+        password: &str,
+    ) -> Result<&[u8], Error> {
+        {
+            let mut req = self
+                .builder
+                .init_root::<request::Builder>()
+                .init_delete_account();
+            req.set_token(token);
+            req.set_password(password);
+        }
+
+        self.write()
+    }
+
     // Should be called when authentication is successful
     // Sends request to server to join websocket chat
     pub fn write_request_connect_to_chat(&mut self, token: &str) -> Result<&[u8], Error> {
@@ -174,6 +389,75 @@ impl ProtocolService {
         self.write()
     }
 
+    /// Read the error of any response that failed, so the UI can react to its code
+    pub fn read_response_error(&self, mut data: &[u8]) -> Result<Option<ProtocolError>, Error> {
+        let reader = serialize_packed::read_message(&mut data, ReaderOptions::new())?;
+        let response = reader.get_root::<response::Reader>()?;
+
+        let error = match response.which()? {
+            response::Login(data) => match data.which()? {
+                response::login::Error(error) => error?,
+                _ => return Ok(None),
+            },
+            response::Logout(data) => match data.which()? {
+                response::logout::Error(error) => error?,
+                _ => return Ok(None),
+            },
+            response::FetchPosts(data) => match data.which()? {
+                response::fetch_posts::Error(error) => error?,
+                _ => return Ok(None),
+            },
+            response::CreatePost(data) => match data.which()? {
+                response::create_post::Error(error) => error?,
+                _ => return Ok(None),
+            },
+            response::UserVote(data) => match data.which()? {
+                response::user_vote::Error(error) => error?,
+                _ => return Ok(None),
+            },
+            response::ConnectToChat(data) => match data.which()? {
+                response::connect_to_chat::Error(error) => error?,
+                _ => return Ok(None),
+            },
+            response::RefreshSession(data) => match data.which()? {
+                response::refresh_session::Error(error) => error?,
+                _ => return Ok(None),
+            },
+            response::ListSessions(data) => match data.which()? {
+                response::list_sessions::Error(error) => error?,
+                _ => return Ok(None),
+            },
+            response::RevokeSession(data) => match data.which()? {
+                response::revoke_session::Error(error) => error?,
+                _ => return Ok(None),
+            },
+            response::ChangePassword(data) => match data.which()? {
+                response::change_password::Error(error) => error?,
+                _ => return Ok(None),
+            },
+            response::DeleteAccount(data) => match data.which()? {
+                response::delete_account::Error(error) => error?,
+                _ => return Ok(None),
+            },
+            response::InvalidRequest(error) => error?,
+            response::JoinRoom(data) => match data.which()? {
+                response::join_room::Error(error) => error?,
+                _ => return Ok(None),
+            },
+            response::LeaveRoom(data) => match data.which()? {
+                response::leave_room::Error(error) => error?,
+                _ => return Ok(None),
+            },
+            response::ListRooms(data) => match data.which()? {
+                response::list_rooms::Error(error) => error?,
+                _ => return Ok(None),
+            },
+            response::Update(_) => return Ok(None),
+        };
+
+        Ok(Some(ProtocolError::read(error)?))
+    }
+
     pub fn read_response_login(&self, mut data: &[u8]) -> Result<Option<LoginResponse>, Error> {
         let reader = serialize_packed::read_message(&mut data, ReaderOptions::new())?;
         let response = reader.get_root::<response::Reader>()?;
@@ -182,9 +466,11 @@ impl ProtocolService {
             response::Login(data) => match data.which()? {
                 response::login::Success(data) => {
                     let token = data.get_token()?.to_string();
+                    let refresh_token = data.get_refresh_token()?.to_string();
                     let user = data.get_user()?;
                     let login_res = LoginResponse {
                         token,
+                        refresh_token,
                         user: User {
                             id: user.get_id(),
                             username: user.get_username()?.to_string(),
@@ -194,9 +480,8 @@ impl ProtocolService {
                     };
                     Ok(Some(login_res))
                 }
-                response::login::Error(error) => Err(Error::from(ProtocolError::Response {
-                    description: error?.to_owned(),
-                })),
+                response::login::Error(error) => Err(ProtocolError::read(error?)?.into()),
+                _ => Ok(None),
             },
             _ => Ok(None),
         }
@@ -209,9 +494,8 @@ impl ProtocolService {
         match response.which()? {
             response::Logout(data) => match data.which()? {
                 response::logout::Success(_) => Ok(Some(())),
-                response::logout::Error(error) => Err(Error::from(ProtocolError::Response {
-                    description: error?.to_owned(),
-                })),
+                response::logout::Error(error) => Err(ProtocolError::read(error?)?.into()),
+                _ => Ok(None),
             },
             _ => Ok(None),
         }
@@ -227,7 +511,6 @@ impl ProtocolService {
         match response.which()? {
             response::FetchPosts(data) => match data.which()? {
                 response::fetch_posts::Success(data) => {
-                    let token = data.get_token()?.to_string();
                     let mut posts = Vec::<Post>::new();
 
                     for post in data.get_posts()?.iter() {
@@ -237,14 +520,24 @@ impl ProtocolService {
                             valid: post.get_valid(),
                             vote: post.get_vote()?.into(),
                             userId: post.get_user_id(),
+                            roomId: post.get_room_id(),
+                            upCount: post.get_up_count(),
+                            downCount: post.get_down_count(),
+                            talliesHidden: post.get_tallies_hidden(),
                         })
                     }
 
-                    Ok(Some(FetchedPosts { token, posts }))
+                    let next_cursor = data.get_next_cursor()?;
+                    let next_cursor = if next_cursor.is_empty() {
+                        None
+                    } else {
+                        Some(next_cursor.to_string())
+                    };
+
+                    Ok(Some(FetchedPosts { posts, next_cursor }))
                 }
-                response::fetch_posts::Error(error) => Err(Error::from(ProtocolError::Response {
-                    description: error?.to_owned(),
-                })),
+                response::fetch_posts::Error(error) => Err(ProtocolError::read(error?)?.into()),
+                _ => Ok(None),
             },
             _ => Ok(None),
         }
@@ -257,7 +550,6 @@ impl ProtocolService {
         match response.which()? {
             response::CreatePost(data) => match data.which()? {
                 response::create_post::Success(data) => {
-                    let token = data.get_token()?.to_string();
                     let post = data.get_post()?;
 
                     let post = Post {
@@ -266,28 +558,30 @@ impl ProtocolService {
                         valid: post.get_valid(),
                         vote: post.get_vote()?.into(),
                         userId: post.get_user_id(),
+                        roomId: post.get_room_id(),
+                        upCount: post.get_up_count(),
+                        downCount: post.get_down_count(),
+                        talliesHidden: post.get_tallies_hidden(),
                     };
 
-                    Ok(Some(CreatedPost { token, post }))
+                    Ok(Some(CreatedPost { post }))
                 }
-                response::create_post::Error(error) => Err(Error::from(ProtocolError::Response {
-                    description: error?.to_owned(),
-                })),
+                response::create_post::Error(error) => Err(ProtocolError::read(error?)?.into()),
+                _ => Ok(None),
             },
             _ => Ok(None),
         }
     }
 
-    pub fn read_request_user_vote(&self, mut data: &[u8]) -> Result<Option<(String)>, Error> {
+    pub fn read_request_user_vote(&self, mut data: &[u8]) -> Result<Option<()>, Error> {
         let reader = serialize_packed::read_message(&mut data, ReaderOptions::new())?;
         let response = reader.get_root::<response::Reader>()?;
 
         match response.which()? {
             response::UserVote(data) => match data.which()? {
-                response::user_vote::Success(data) => Ok(Some(data?.to_string())),
-                response::user_vote::Error(error) => Err(Error::from(ProtocolError::Response {
-                    description: error?.to_owned(),
-                })),
+                response::user_vote::Success(_) => Ok(Some(())),
+                response::user_vote::Error(error) => Err(ProtocolError::read(error?)?.into()),
+                _ => Ok(None),
             },
             _ => Ok(None),
         }
@@ -307,6 +601,10 @@ impl ProtocolService {
                         valid: post.get_valid(),
                         vote: post.get_vote()?.into(),
                         userId: post.get_user_id(),
+                        roomId: post.get_room_id(),
+                        upCount: post.get_up_count(),
+                        downCount: post.get_down_count(),
+                        talliesHidden: post.get_tallies_hidden(),
                     };
 
                     Ok(Some(post))
@@ -338,6 +636,26 @@ impl ProtocolService {
         }
     }
 
+    pub fn read_update_vote_tally(&self, mut data: &[u8]) -> Result<Option<VoteTally>, Error> {
+        let reader = serialize_packed::read_message(&mut data, ReaderOptions::new())?;
+        let response = reader.get_root::<response::Reader>()?;
+
+        match response.which()? {
+            response::Update(data) => match data?.which()? {
+                update::VoteTally(data) => {
+                    let tally = data?;
+                    Ok(Some(VoteTally {
+                        postId: tally.get_post_id(),
+                        upCount: tally.get_up_count(),
+                        downCount: tally.get_down_count(),
+                    }))
+                }
+                _ => Ok(None),
+            },
+            _ => Ok(None),
+        }
+    }
+
     pub fn read_update_users(&self, mut data: &[u8]) -> Result<Option<UsersToUpdate>, Error> {
         let reader = serialize_packed::read_message(&mut data, ReaderOptions::new())?;
         let response = reader.get_root::<response::Reader>()?;
@@ -370,9 +688,165 @@ impl ProtocolService {
         match response.which()? {
             response::ConnectToChat(data) => match data.which()? {
                 response::connect_to_chat::Success(_) => Ok(Some(())),
-                response::connect_to_chat::Error(_) => Err(Error::from(ProtocolError::Response {
-                    description: "Connecting to chat was unsuccessful".to_string(),
-                })),
+                response::connect_to_chat::Error(error) => {
+                    Err(ProtocolError::read(error?)?.into())
+                }
+                _ => Ok(None),
+            },
+            _ => Ok(None),
+        }
+    }
+
+    pub fn read_response_refresh_session(
+        &self,
+        mut data: &[u8],
+    ) -> Result<Option<LoginResponse>, Error> {
+        let reader = serialize_packed::read_message(&mut data, ReaderOptions::new())?;
+        let response = reader.get_root::<response::Reader>()?;
+
+        match response.which()? {
+            response::RefreshSession(data) => match data.which()? {
+                response::refresh_session::Success(data) => {
+                    let user = data.get_user()?;
+                    Ok(Some(LoginResponse {
+                        token: data.get_token()?.to_string(),
+                        refresh_token: data.get_refresh_token()?.to_string(),
+                        user: User {
+                            id: user.get_id(),
+                            username: user.get_username()?.to_string(),
+                            karma: user.get_karma(),
+                            streak: user.get_streak(),
+                        },
+                    }))
+                }
+                response::refresh_session::Error(error) => Err(ProtocolError::read(error?)?.into()),
+            },
+            _ => Ok(None),
+        }
+    }
+
+    pub fn read_response_list_sessions(
+        &self,
+        mut data: &[u8],
+    ) -> Result<Option<Vec<SessionInfo>>, Error> {
+        let reader = serialize_packed::read_message(&mut data, ReaderOptions::new())?;
+        let response = reader.get_root::<response::Reader>()?;
+
+        match response.which()? {
+            response::ListSessions(data) => match data.which()? {
+                response::list_sessions::Success(data) => {
+                    let mut sessions = Vec::new();
+                    for session in data?.iter() {
+                        sessions.push(SessionInfo {
+                            id: session.get_id()?.to_string(),
+                            createdAt: session.get_created_at(),
+                            expiresAt: session.get_expires_at(),
+                            lastSeenAt: session.get_last_seen_at(),
+                            userAgent: session.get_user_agent()?.to_string(),
+                            ipAddress: session.get_ip_address()?.to_string(),
+                            current: session.get_current(),
+                        });
+                    }
+
+                    Ok(Some(sessions))
+                }
+                response::list_sessions::Error(error) => Err(ProtocolError::read(error?)?.into()),
+            },
+            _ => Ok(None),
+        }
+    }
+
+    pub fn read_response_revoke_session(&self, mut data: &[u8]) -> Result<Option<()>, Error> {
+        let reader = serialize_packed::read_message(&mut data, ReaderOptions::new())?;
+        let response = reader.get_root::<response::Reader>()?;
+
+        match response.which()? {
+            response::RevokeSession(data) => match data.which()? {
+                response::revoke_session::Success(_) => Ok(Some(())),
+                response::revoke_session::Error(error) => Err(ProtocolError::read(error?)?.into()),
+            },
+            _ => Ok(None),
+        }
+    }
+
+    pub fn read_response_change_password(&self, mut data: &[u8]) -> Result<Option<()>, Error> {
+        let reader = serialize_packed::read_message(&mut data, ReaderOptions::new())?;
+        let response = reader.get_root::<response::Reader>()?;
+
+        match response.which()? {
+            response::ChangePassword(data) => match data.which()? {
+                response::change_password::Success(_) => Ok(Some(())),
+                response::change_password::Error(error) => {
+                    Err(ProtocolError::read(error?)?.into())
+                }
+            },
+            _ => Ok(None),
+        }
+    }
+
+    pub fn read_response_delete_account(&self, mut data: &[u8]) -> Result<Option<()>, Error> {
+        let reader = serialize_packed::read_message(&mut data, ReaderOptions::new())?;
+        let response = reader.get_root::<response::Reader>()?;
+
+        match response.which()? {
+            response::DeleteAccount(data) => match data.which()? {
+                response::delete_account::Success(_) => Ok(Some(())),
+                response::delete_account::Error(error) => Err(ProtocolError::read(error?)?.into()),
+            },
+            _ => Ok(None),
+        }
+    }
+
+    pub fn read_response_join_room(&self, mut data: &[u8]) -> Result<Option<Room>, Error> {
+        let reader = serialize_packed::read_message(&mut data, ReaderOptions::new())?;
+        let response = reader.get_root::<response::Reader>()?;
+
+        match response.which()? {
+            response::JoinRoom(data) => match data.which()? {
+                response::join_room::Success(room) => {
+                    let room = room?;
+                    Ok(Some(Room {
+                        id: room.get_id(),
+                        name: room.get_name()?.to_string(),
+                    }))
+                }
+                response::join_room::Error(error) => Err(ProtocolError::read(error?)?.into()),
+            },
+            _ => Ok(None),
+        }
+    }
+
+    pub fn read_response_leave_room(&self, mut data: &[u8]) -> Result<Option<()>, Error> {
+        let reader = serialize_packed::read_message(&mut data, ReaderOptions::new())?;
+        let response = reader.get_root::<response::Reader>()?;
+
+        match response.which()? {
+            response::LeaveRoom(data) => match data.which()? {
+                response::leave_room::Success(_) => Ok(Some(())),
+                response::leave_room::Error(error) => Err(ProtocolError::read(error?)?.into()),
+            },
+            _ => Ok(None),
+        }
+    }
+
+    pub fn read_response_list_rooms(&self, mut data: &[u8]) -> Result<Option<Vec<Room>>, Error> {
+        let reader = serialize_packed::read_message(&mut data, ReaderOptions::new())?;
+        let response = reader.get_root::<response::Reader>()?;
+
+        match response.which()? {
+            response::ListRooms(data) => match data.which()? {
+                response::list_rooms::Success(data) => {
+                    let mut rooms = Vec::new();
+                    for room in data?.iter() {
+                        rooms.push(Room {
+                            id: room.get_id(),
+                            name: room.get_name()?.to_string(),
+                        });
+                    }
+
+                    Ok(Some(rooms))
+                }
+                response::list_rooms::Error(error) => Err(ProtocolError::read(error?)?.into()),
             },
             _ => Ok(None),
         }
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE sessions;
+CREATE TABLE sessions (
+    id TEXT PRIMARY KEY
+)
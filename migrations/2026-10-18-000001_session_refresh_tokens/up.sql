@@ -0,0 +1,10 @@
+-- Sessions used to be keyed by the full access token, which changed on every
+-- request. They are now identified by a stable id and hold a hashed refresh
+-- token instead, existing rows cannot be carried over.
+DROP TABLE sessions;
+CREATE TABLE sessions (
+    id TEXT PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users (id),
+    refresh_token TEXT NOT NULL UNIQUE,
+    expires_at TIMESTAMP NOT NULL
+)
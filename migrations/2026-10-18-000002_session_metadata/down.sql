@@ -0,0 +1,9 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX sessions_expires_at_idx;
+DROP INDEX sessions_user_id_idx;
+
+ALTER TABLE sessions
+    DROP COLUMN ip_address,
+    DROP COLUMN user_agent,
+    DROP COLUMN last_seen_at,
+    DROP COLUMN created_at
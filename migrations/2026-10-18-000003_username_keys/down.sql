@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS users_username_key_idx;
+ALTER TABLE users DROP COLUMN username_key
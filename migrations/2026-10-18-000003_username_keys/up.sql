@@ -0,0 +1,5 @@
+-- Usernames are unique regardless of case, `username_key` holds the
+-- normalized and case folded form they are compared by. The backend fills it
+-- in with `validation::username_key` right after migrating, then makes it
+-- NOT NULL and unique (see `database::username_keys`).
+ALTER TABLE users ADD COLUMN username_key TEXT
@@ -0,0 +1,18 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE votes
+    DROP CONSTRAINT votes_post_id_fkey,
+    ADD CONSTRAINT votes_post_id_fkey
+        FOREIGN KEY (post_id) REFERENCES posts (id),
+    DROP CONSTRAINT votes_user_id_fkey,
+    ADD CONSTRAINT votes_user_id_fkey
+        FOREIGN KEY (user_id) REFERENCES users (id);
+
+ALTER TABLE posts
+    DROP CONSTRAINT posts_user_id_fkey,
+    ADD CONSTRAINT posts_user_id_fkey
+        FOREIGN KEY (user_id) REFERENCES users (id);
+
+ALTER TABLE sessions
+    DROP CONSTRAINT sessions_user_id_fkey,
+    ADD CONSTRAINT sessions_user_id_fkey
+        FOREIGN KEY (user_id) REFERENCES users (id);
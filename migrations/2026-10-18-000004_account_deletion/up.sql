@@ -0,0 +1,20 @@
+-- Deleting an account deletes everything it owns: its sessions, its votes and
+-- its posts, along with the votes other users cast on those posts. Karma that
+-- was already settled for closed posts is kept.
+ALTER TABLE sessions
+    DROP CONSTRAINT sessions_user_id_fkey,
+    ADD CONSTRAINT sessions_user_id_fkey
+        FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE;
+
+ALTER TABLE posts
+    DROP CONSTRAINT posts_user_id_fkey,
+    ADD CONSTRAINT posts_user_id_fkey
+        FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE;
+
+ALTER TABLE votes
+    DROP CONSTRAINT votes_user_id_fkey,
+    ADD CONSTRAINT votes_user_id_fkey
+        FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE,
+    DROP CONSTRAINT votes_post_id_fkey,
+    ADD CONSTRAINT votes_post_id_fkey
+        FOREIGN KEY (post_id) REFERENCES posts (id) ON DELETE CASCADE;
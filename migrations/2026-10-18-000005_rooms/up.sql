@@ -0,0 +1,15 @@
+-- Posts are made in rooms. The general room has id 0 so that clients which do
+-- not know about rooms, and every post made before them, end up there.
+CREATE TABLE rooms (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+INSERT INTO rooms (id, name) VALUES (0, 'general');
+
+ALTER TABLE posts
+    ADD COLUMN room_id INTEGER NOT NULL DEFAULT 0
+        REFERENCES rooms (id) ON DELETE CASCADE;
+
+CREATE INDEX posts_room_id_idx ON posts (room_id);
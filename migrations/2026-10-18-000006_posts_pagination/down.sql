@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX posts_room_id_created_at_idx;
+
+CREATE INDEX posts_room_id_idx ON posts (room_id);
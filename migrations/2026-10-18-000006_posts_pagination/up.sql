@@ -0,0 +1,4 @@
+-- Pages of posts are read in the order they were made, one room at a time
+DROP INDEX posts_room_id_idx;
+
+CREATE INDEX posts_room_id_created_at_idx ON posts (room_id, created_at, id);
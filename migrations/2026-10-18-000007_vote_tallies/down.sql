@@ -0,0 +1,8 @@
+-- This file should undo anything in `up.sql`
+DROP TRIGGER votes_tally ON votes;
+
+DROP FUNCTION tally_vote();
+
+ALTER TABLE posts
+    DROP COLUMN up_count,
+    DROP COLUMN down_count;
@@ -0,0 +1,41 @@
+-- Every post counts its up and down votes, kept up to date by a trigger so
+-- that votes removed along with their user are taken off the tally too
+ALTER TABLE posts
+    ADD COLUMN up_count INTEGER NOT NULL DEFAULT 0,
+    ADD COLUMN down_count INTEGER NOT NULL DEFAULT 0;
+
+UPDATE posts
+SET up_count = tally.up_count, down_count = tally.down_count
+FROM (
+    SELECT
+        post_id,
+        COUNT(*) FILTER (WHERE up_or_down = 1) AS up_count,
+        COUNT(*) FILTER (WHERE up_or_down = -1) AS down_count
+    FROM votes
+    GROUP BY post_id
+) AS tally
+WHERE posts.id = tally.post_id;
+
+CREATE FUNCTION tally_vote() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP IN ('UPDATE', 'DELETE') THEN
+        UPDATE posts
+        SET up_count = up_count - (OLD.up_or_down = 1)::INTEGER,
+            down_count = down_count - (OLD.up_or_down = -1)::INTEGER
+        WHERE id = OLD.post_id;
+    END IF;
+
+    IF TG_OP IN ('INSERT', 'UPDATE') THEN
+        UPDATE posts
+        SET up_count = up_count + (NEW.up_or_down = 1)::INTEGER,
+            down_count = down_count + (NEW.up_or_down = -1)::INTEGER
+        WHERE id = NEW.post_id;
+    END IF;
+
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER votes_tally
+    AFTER INSERT OR UPDATE OR DELETE ON votes
+    FOR EACH ROW EXECUTE PROCEDURE tally_vote();